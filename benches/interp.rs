@@ -0,0 +1,46 @@
+// Run with `cargo bench`; prints the mean time per run of every case.
+use rfort::ForthInterp;
+use std::time::{Duration, Instant};
+
+const CASES: &[(&str, &str, &str)] = &[
+    (
+        "deep recursion",
+        ": down dup if 1- down then ;",
+        "2000 down drop",
+    ),
+    (
+        "fibonacci",
+        ": fib dup 2 < if else dup 1- fib swap 2 - fib + then ;",
+        "20 fib drop",
+    ),
+    ("tight until loop", "", "100000 begin 1- dup 0= until drop"),
+    (
+        "tight while loop",
+        "variable n",
+        "0 n ! begin n @ 100000 < while n @ 1+ n ! repeat",
+    ),
+    (
+        "word calls in a loop",
+        ": sq dup * ; : step 3 sq drop ;",
+        "100000 begin step 1- dup 0= until drop",
+    ),
+];
+
+fn bench(setup: &str, code: &str) -> Duration {
+    let mut interp = ForthInterp::new();
+    interp.eval_str(setup).unwrap();
+    interp.eval_str(code).unwrap();
+    let mut runs = 0;
+    let start = Instant::now();
+    while runs < 5 || start.elapsed() < Duration::from_secs(1) {
+        interp.eval_str(code).unwrap();
+        runs += 1;
+    }
+    start.elapsed() / runs
+}
+
+fn main() {
+    for (name, setup, code) in CASES {
+        println!("{:<24} {:>12.3?}", name, bench(setup, code));
+    }
+}
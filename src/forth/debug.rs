@@ -0,0 +1,313 @@
+use super::interp::ForthInterp;
+use super::types::*;
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+
+pub struct TraceStep {
+    pub word: String,
+    pub depth: usize,
+    pub call: bool,
+}
+
+// Hooks run by ForthInterp::eval around every instruction it executes.
+pub trait Tracer {
+    fn before(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()>;
+    fn after(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()>;
+}
+
+fn format_stack(stack: &[i64]) -> String {
+    let mut res = String::from("[");
+    for num in stack {
+        res.push_str(&format!(" {}", num));
+    }
+    res.push_str(" ]");
+    res
+}
+
+fn io_err(e: io::Error) -> ForthErr {
+    ForthErr::Msg(format!("Trace output failed: {}", e))
+}
+
+pub struct StackTracer {
+    out: Box<dyn Write>,
+    before: Vec<Vec<i64>>,
+}
+
+impl StackTracer {
+    pub fn new(out: Box<dyn Write>) -> StackTracer {
+        StackTracer {
+            out,
+            before: vec![],
+        }
+    }
+
+    pub fn stderr() -> StackTracer {
+        StackTracer::new(Box::new(io::stderr()))
+    }
+}
+
+impl Tracer for StackTracer {
+    fn before(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+        if step.call {
+            writeln!(
+                self.out,
+                "{:>3} {:indent$}-> {} {}",
+                step.depth,
+                "",
+                step.word,
+                format_stack(interp.stack()),
+                indent = step.depth * 2
+            )
+            .map_err(io_err)?;
+        }
+        self.before.push(interp.stack().to_vec());
+        Ok(())
+    }
+
+    fn after(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+        let before = self.before.pop().unwrap_or_default();
+        if step.call {
+            writeln!(
+                self.out,
+                "{:>3} {:indent$}<- {} {}",
+                step.depth,
+                "",
+                step.word,
+                format_stack(interp.stack()),
+                indent = step.depth * 2
+            )
+        } else {
+            writeln!(
+                self.out,
+                "{:>3} {:indent$}{} {} -> {}",
+                step.depth,
+                "",
+                step.word,
+                format_stack(&before),
+                format_stack(interp.stack()),
+                indent = step.depth * 2
+            )
+        }
+        .map_err(io_err)
+    }
+}
+
+// Reads stdin a line at a time so that nothing is buffered away from the REPL.
+#[derive(Default)]
+struct StdinLines {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl io::Read for StdinLines {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let buf = self.fill_buf()?;
+            let n = buf.len().min(out.len());
+            out[..n].copy_from_slice(&buf[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.buf.len() {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            self.buf = line.into_bytes();
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Mode {
+    Run,
+    StepInto,
+    StepOver(usize),
+    StepOut(usize),
+}
+
+const DEBUGGER_HELP: &str = "\
+s, step        run the next instruction, entering calls
+n, next        run the next instruction, stepping over calls
+o, out         run until the current word returns
+c, continue    run until the next breakpoint
+st, stack      show the data stack
+bt, calls      show the words being executed
+v, var NAME    show a variable; without NAME show all of them
+b NAME         set a breakpoint on NAME
+d NAME         delete the breakpoint on NAME
+q, quit        abort the evaluation";
+
+// Interactive single-step debugger that reads commands from `input` whenever
+// execution pauses at a breakpoint or after a step.
+pub struct Debugger {
+    breakpoints: HashSet<String>,
+    mode: Mode,
+    calls: Vec<String>,
+    trace: Option<StackTracer>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl Debugger {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Debugger {
+        Debugger {
+            breakpoints: HashSet::new(),
+            mode: Mode::Run,
+            calls: vec![],
+            trace: None,
+            input,
+            output,
+        }
+    }
+
+    pub fn stdio() -> Debugger {
+        Debugger::new(Box::new(StdinLines::default()), Box::new(io::stdout()))
+    }
+
+    pub fn add_breakpoint(&mut self, word: &str) {
+        self.breakpoints.insert(word.to_ascii_lowercase());
+    }
+
+    pub fn remove_breakpoint(&mut self, word: &str) -> bool {
+        self.breakpoints.remove(&word.to_ascii_lowercase())
+    }
+
+    pub fn breakpoints(&self) -> Vec<String> {
+        let mut res: Vec<String> = self.breakpoints.iter().cloned().collect();
+        res.sort();
+        res
+    }
+
+    pub fn set_trace(&mut self, trace: Option<StackTracer>) {
+        self.trace = trace;
+    }
+
+    // Pauses before the next instruction that runs.
+    pub fn step(&mut self) {
+        self.mode = Mode::StepInto;
+    }
+
+    pub fn resume(&mut self) {
+        self.mode = Mode::Run;
+        self.calls.clear();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.breakpoints.is_empty() || self.trace.is_some() || self.mode != Mode::Run
+    }
+
+    fn pause(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+        writeln!(
+            self.output,
+            "{} at depth {} {}",
+            step.word,
+            step.depth,
+            format_stack(interp.stack())
+        )
+        .map_err(io_err)?;
+        loop {
+            write!(self.output, "dbg> ").map_err(io_err)?;
+            self.output.flush().map_err(io_err)?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line).map_err(io_err)? == 0 {
+                self.mode = Mode::Run;
+                return Ok(());
+            }
+            let mut args = line.split_whitespace();
+            let reply = match args.next().unwrap_or("s") {
+                "s" | "step" => {
+                    self.mode = Mode::StepInto;
+                    return Ok(());
+                }
+                "n" | "next" => {
+                    self.mode = Mode::StepOver(step.depth);
+                    return Ok(());
+                }
+                "o" | "out" => {
+                    self.mode = Mode::StepOut(step.depth);
+                    return Ok(());
+                }
+                "c" | "continue" => {
+                    self.mode = Mode::Run;
+                    return Ok(());
+                }
+                "q" | "quit" => {
+                    self.resume();
+                    return Err(ForthErr::Interrupted);
+                }
+                "st" | "stack" => format_stack(interp.stack()),
+                "bt" | "calls" => self.calls.join(" > "),
+                "v" | "var" => match args.next() {
+                    Some(name) => match interp.variable(name) {
+                        Some(num) => format!("{} = {}", name, num),
+                        None => format!("No variable {}", name),
+                    },
+                    None => interp
+                        .variables()
+                        .iter()
+                        .map(|(name, num)| format!("{} = {}", name, num))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                },
+                "b" => match args.next() {
+                    Some(name) => {
+                        self.add_breakpoint(name);
+                        format!("Breakpoint on {}", name)
+                    }
+                    None => self.breakpoints().join(" "),
+                },
+                "d" => match args.next() {
+                    Some(name) if self.remove_breakpoint(name) => {
+                        format!("Deleted breakpoint on {}", name)
+                    }
+                    _ => "No such breakpoint".to_string(),
+                },
+                "h" | "help" => DEBUGGER_HELP.to_string(),
+                cmd => format!("Unknown command {}, try help", cmd),
+            };
+            writeln!(self.output, "{}", reply).map_err(io_err)?;
+        }
+    }
+}
+
+impl Tracer for Debugger {
+    fn before(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+        let pause = match self.mode {
+            Mode::Run => false,
+            Mode::StepInto => true,
+            Mode::StepOver(depth) => step.depth <= depth,
+            Mode::StepOut(depth) => step.depth < depth,
+        };
+        if pause || self.breakpoints.contains(&step.word) {
+            self.pause(interp, step)?;
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.before(interp, step)?;
+        }
+        if step.call {
+            self.calls.push(step.word.clone());
+        }
+        Ok(())
+    }
+
+    fn after(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+        if step.call {
+            self.calls.pop();
+        }
+        match &mut self.trace {
+            Some(trace) => trace.after(interp, step),
+            None => Ok(()),
+        }
+    }
+}
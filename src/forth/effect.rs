@@ -0,0 +1,332 @@
+use super::types::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// Number of cells a word takes from and leaves on the data stack.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    fn new(inputs: usize, outputs: usize) -> StackEffect {
+        StackEffect { inputs, outputs }
+    }
+
+    fn net(self) -> i64 {
+        self.outputs as i64 - self.inputs as i64
+    }
+
+    // Reads a `( a b -- c )` comment; `None` if it isn't a plain stack picture.
+    pub(crate) fn parse(comment: &[String]) -> Option<StackEffect> {
+        if comment.iter().filter(|t| *t == "--").count() != 1 {
+            return None;
+        }
+        let sep = comment.iter().position(|t| t == "--")?;
+        let items = |items: &[String]| {
+            if items.iter().any(|t| t.contains('*') || t.contains("..")) {
+                None
+            } else {
+                Some(items.len())
+            }
+        };
+        Some(StackEffect::new(
+            items(&comment[..sep])?,
+            items(&comment[sep + 1..])?,
+        ))
+    }
+}
+
+impl fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "( {} -- {} )", self.inputs, self.outputs)
+    }
+}
+
+// Stack depth relative to the start of a sequence and the lowest point reached.
+#[derive(Clone, Copy, Default)]
+struct Depth {
+    depth: i64,
+    min: i64,
+}
+
+impl Depth {
+    fn apply(&mut self, effect: StackEffect) {
+        self.depth -= effect.inputs as i64;
+        self.min = self.min.min(self.depth);
+        self.depth += effect.outputs as i64;
+    }
+
+    fn effect(self) -> StackEffect {
+        StackEffect::new(-self.min as usize, (self.depth - self.min) as usize)
+    }
+}
+
+fn primitive(op: &ForthOp) -> Option<StackEffect> {
+    let (inputs, outputs) = match op {
+        ForthOp::Add | ForthOp::Sub | ForthOp::Mul | ForthOp::Div | ForthOp::Mod => (2, 1),
+        ForthOp::DivMod => (2, 2),
+        ForthOp::MulDiv => (3, 1),
+        ForthOp::MulDivMod => (3, 2),
+        ForthOp::Abs
+        | ForthOp::Neg
+        | ForthOp::Add1
+        | ForthOp::Sub1
+        | ForthOp::Add2
+        | ForthOp::Sub2
+        | ForthOp::Mul2
+        | ForthOp::Div2 => (1, 1),
+        ForthOp::Dup => (1, 2),
+        ForthOp::Drop => (1, 0),
+        ForthOp::Over => (2, 3),
+        ForthOp::Rot => (3, 3),
+        ForthOp::Swap => (2, 2),
+        ForthOp::Print | ForthOp::PrintAt | ForthOp::Emit => (1, 0),
+        ForthOp::PrintStack => (0, 0),
+        ForthOp::Type | ForthOp::Dump => (2, 0),
+        ForthOp::Key => (0, 1),
+        ForthOp::Accept => (2, 1),
+        ForthOp::And | ForthOp::Or | ForthOp::Xor => (2, 1),
+        ForthOp::Not | ForthOp::Invert => (1, 1),
+        ForthOp::Lt | ForthOp::Gt | ForthOp::Eq | ForthOp::Le | ForthOp::Ge | ForthOp::Ne => (2, 1),
+        ForthOp::Lt0 | ForthOp::Eq0 | ForthOp::Gt0 | ForthOp::Ne0 => (1, 1),
+        ForthOp::True | ForthOp::False => (0, 1),
+        ForthOp::Dup2 => (2, 4),
+        ForthOp::Swap2 => (4, 4),
+        ForthOp::Drop2 => (2, 0),
+        ForthOp::Over2 => (4, 6),
+        ForthOp::Depth => (0, 1),
+        ForthOp::Lshift | ForthOp::Rshift | ForthOp::Max | ForthOp::Min => (2, 1),
+        ForthOp::Fetch | ForthOp::CFetch => (1, 1),
+        ForthOp::Store | ForthOp::AddStore | ForthOp::CStore => (2, 0),
+        ForthOp::TwoFetch => (1, 2),
+        ForthOp::TwoStore => (3, 0),
+        ForthOp::Here => (0, 1),
+        ForthOp::Allot | ForthOp::Comma | ForthOp::CComma => (1, 0),
+        ForthOp::Cells | ForthOp::CellAdd | ForthOp::Chars | ForthOp::CharAdd => (1, 1),
+        ForthOp::Compare => (4, 1),
+        ForthOp::Search => (4, 3),
+        ForthOp::SlashString => (3, 2),
+        ForthOp::DashTrailing => (2, 2),
+        ForthOp::Blank | ForthOp::Erase => (2, 0),
+        ForthOp::Fill | ForthOp::Move | ForthOp::CMove | ForthOp::CMoveUp => (3, 0),
+        ForthOp::SPlus => (4, 2),
+        ForthOp::ReadOnly | ForthOp::WriteOnly | ForthOp::ReadWrite => (0, 1),
+        ForthOp::Bin | ForthOp::CloseFile => (1, 1),
+        ForthOp::CreateFile | ForthOp::OpenFile | ForthOp::ReadFile => (3, 2),
+        ForthOp::ReadLine => (3, 3),
+        ForthOp::WriteFile | ForthOp::WriteLine | ForthOp::RepositionFile => (3, 1),
+        ForthOp::FileSize | ForthOp::FilePosition => (1, 3),
+        ForthOp::DeleteFile => (2, 1),
+        ForthOp::RenameFile => (4, 1),
+        ForthOp::Allocate => (1, 2),
+        ForthOp::Free => (1, 1),
+        ForthOp::Resize => (2, 2),
+        ForthOp::DeferFetch => (1, 1),
+        ForthOp::DeferStore => (2, 0),
+        ForthOp::Variable(_)
+        | ForthOp::TwoVariable(_)
+        | ForthOp::Defer(_)
+        | ForthOp::SaveSystem(_) => (0, 0),
+        ForthOp::Constant(_) | ForthOp::Value(_) => (1, 0),
+        ForthOp::TwoConstant(_) | ForthOp::TwoValue(_) => (2, 0),
+        ForthOp::TestStart(_) => (0, 0),
+        ForthOp::Locals(args, _) => (*args, 0),
+        ForthOp::Local(_) => (0, 1),
+        ForthOp::LocalStore(_) => (1, 0),
+        ForthOp::Tick(_) | ForthOp::ActionOf(_) => (0, 1),
+        ForthOp::SQuote(_) => (0, 2),
+        ForthOp::Is(_) | ForthOp::PlusTo(_) => (1, 0),
+        // The effect of these depends on values only known at run time.
+        ForthOp::Pick
+        | ForthOp::Roll
+        | ForthOp::IfDup
+        | ForthOp::Execute
+        | ForthOp::EnvironmentQuery => return None,
+        // TO takes one or two cells depending on the value.
+        ForthOp::To(_) => return None,
+        ForthOp::TestArrow | ForthOp::TestEnd => return None,
+        ForthOp::UserWord(_)
+        | ForthOp::IfThenElse(_)
+        | ForthOp::BeginUntil(_)
+        | ForthOp::BeginWhile(_) => return None,
+    };
+    Some(StackEffect::new(inputs, outputs))
+}
+
+// Infers stack effects of compiled bodies. Anything whose effect can't be
+// known statically (EXECUTE, host natives, unbalanced loops) makes the
+// enclosing effect unknown rather than an error.
+pub(crate) struct EffectChecker<'a> {
+    new_words: &'a HashMap<ForthOp, ForthFunc>,
+    words: &'a HashMap<ForthOp, ForthFunc>,
+    declared: &'a HashMap<String, StackEffect>,
+    variables: &'a HashMap<String, i64>,
+    values: &'a HashMap<String, (i64, usize)>,
+    deferred: &'a HashMap<String, i64>,
+    pub issues: Vec<String>,
+    visiting: HashSet<String>,
+}
+
+impl<'a> EffectChecker<'a> {
+    pub fn new(
+        new_words: &'a HashMap<ForthOp, ForthFunc>,
+        words: &'a HashMap<ForthOp, ForthFunc>,
+        declared: &'a HashMap<String, StackEffect>,
+        variables: &'a HashMap<String, i64>,
+        values: &'a HashMap<String, (i64, usize)>,
+        deferred: &'a HashMap<String, i64>,
+    ) -> EffectChecker<'a> {
+        EffectChecker {
+            new_words,
+            words,
+            declared,
+            variables,
+            values,
+            deferred,
+            issues: vec![],
+            visiting: HashSet::new(),
+        }
+    }
+
+    fn func(&self, op: &ForthOp) -> Option<&'a ForthFunc> {
+        self.new_words.get(op).or_else(|| self.words.get(op))
+    }
+
+    // Checks a new definition, recording a mismatch with its declared effect.
+    pub fn check(&mut self, name: &str, declared: Option<StackEffect>) {
+        let body = match self.func(&ForthOp::UserWord(name.to_string())) {
+            Some(ForthFunc::User(body)) => body,
+            _ => return,
+        };
+        self.visiting.insert(name.to_string());
+        let inferred = self.body(name, body);
+        self.visiting.remove(name);
+        if let (Some(inferred), Some(declared)) = (inferred, declared) {
+            if inferred != declared {
+                self.issues.push(format!(
+                    "{}: stack effect is {} but the comment declares {}",
+                    name, inferred, declared
+                ));
+            }
+        }
+    }
+
+    pub fn effect_of(&mut self, name: &str) -> Option<StackEffect> {
+        let effect = self.word(name);
+        self.issues.clear();
+        effect
+    }
+
+    fn word(&mut self, name: &str) -> Option<StackEffect> {
+        if let Some(effect) = self.declared.get(name) {
+            return Some(*effect);
+        }
+        if self.variables.contains_key(name) {
+            return Some(StackEffect::new(0, 1));
+        }
+        if let Some((_, cells)) = self.values.get(name) {
+            return Some(StackEffect::new(0, *cells));
+        }
+        if self.deferred.contains_key(name) || self.visiting.contains(name) {
+            return None;
+        }
+        match self.func(&ForthOp::UserWord(name.to_string()))? {
+            ForthFunc::User(body) => {
+                self.visiting.insert(name.to_string());
+                let mut issues = std::mem::take(&mut self.issues);
+                let res = self.body(name, body);
+                // Problems inside other words were reported when they were defined.
+                std::mem::swap(&mut issues, &mut self.issues);
+                self.visiting.remove(name);
+                res
+            }
+            ForthFunc::Constant(_) => Some(StackEffect::new(0, 1)),
+            ForthFunc::TwoConstant(..) => Some(StackEffect::new(0, 2)),
+            _ => None,
+        }
+    }
+
+    fn op(&mut self, name: &str, op: &ForthOp) -> Option<StackEffect> {
+        match op {
+            ForthOp::UserWord(word) => self.word(word),
+            ForthOp::IfThenElse(_) => match self.func(op)? {
+                ForthFunc::IfThenElse((then, r#else)) => {
+                    let then = self.body(name, then)?;
+                    let r#else = match r#else {
+                        Some(body) => self.body(name, body)?,
+                        None => StackEffect::new(0, 0),
+                    };
+                    if then.net() != r#else.net() {
+                        self.issues.push(format!(
+                            "{}: IF branches leave different stack depths ({:+} and {:+})",
+                            name,
+                            then.net(),
+                            r#else.net()
+                        ));
+                        return None;
+                    }
+                    let inputs = then.inputs.max(r#else.inputs);
+                    let mut depth = Depth::default();
+                    depth.apply(StackEffect::new(1, 0));
+                    depth.apply(StackEffect::new(
+                        inputs,
+                        (inputs as i64 + then.net()) as usize,
+                    ));
+                    Some(depth.effect())
+                }
+                _ => None,
+            },
+            ForthOp::BeginUntil(_) => match self.func(op)? {
+                ForthFunc::BeginUntil(body) => {
+                    let mut depth = Depth::default();
+                    depth.apply(self.body(name, body)?);
+                    depth.apply(StackEffect::new(1, 0));
+                    if depth.depth != 0 {
+                        return None;
+                    }
+                    Some(depth.effect())
+                }
+                _ => None,
+            },
+            ForthOp::BeginWhile(_) => match self.func(op)? {
+                ForthFunc::BeginWhile(head, body) => {
+                    let mut exit = Depth::default();
+                    exit.apply(self.body(name, head)?);
+                    exit.apply(StackEffect::new(1, 0));
+                    let mut cycle = exit;
+                    cycle.apply(self.body(name, body)?);
+                    if cycle.depth != 0 {
+                        return None;
+                    }
+                    exit.min = exit.min.min(cycle.min);
+                    Some(exit.effect())
+                }
+                _ => None,
+            },
+            op => primitive(op),
+        }
+    }
+
+    fn body(&mut self, name: &str, body: &[ForthExp]) -> Option<StackEffect> {
+        let mut depth = Depth::default();
+        let mut known = true;
+        for exp in body {
+            match exp {
+                ForthExp::Number(_) => depth.apply(StackEffect::new(0, 1)),
+                // Keep going after an unknown effect so that branch problems
+                // later in the body are still reported.
+                ForthExp::Op(op) => match self.op(name, op) {
+                    Some(effect) => depth.apply(effect),
+                    None => known = false,
+                },
+            }
+        }
+        if known {
+            Some(depth.effect())
+        } else {
+            None
+        }
+    }
+}
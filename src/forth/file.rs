@@ -0,0 +1,142 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+// File access methods as R/O, W/O and R/W push them; BIN sets FAM_BIN.
+pub(crate) const FAM_READ: i64 = 1;
+pub(crate) const FAM_WRITE: i64 = 2;
+pub(crate) const FAM_BIN: i64 = 4;
+
+// I/O results use the standard THROW codes: -38 for a file that doesn't
+// exist, -37 for any other failure.
+pub(crate) type Ior = i64;
+const IOR_NO_FILE: Ior = -38;
+pub(crate) const IOR_IO: Ior = -37;
+
+fn ior(e: io::Error) -> Ior {
+    match e.kind() {
+        io::ErrorKind::NotFound => IOR_NO_FILE,
+        _ => IOR_IO,
+    }
+}
+
+#[derive(Default)]
+struct Files {
+    open: HashMap<i64, BufReader<File>>,
+    next: i64,
+}
+
+// Files opened by Forth code, by fileid. Snapshots of the interpreter share
+// the table: files are outside of what a failed line can undo.
+#[derive(Clone, Default)]
+pub(crate) struct FileTable {
+    files: Rc<RefCell<Files>>,
+}
+
+impl FileTable {
+    pub fn open(&self, path: &str, fam: i64, create: bool) -> Result<i64, Ior> {
+        let file = OpenOptions::new()
+            .read(fam & FAM_READ != 0)
+            .write(fam & FAM_WRITE != 0)
+            .create(create)
+            .truncate(create)
+            .open(path)
+            .map_err(ior)?;
+        let mut files = self.files.borrow_mut();
+        files.next += 1;
+        let id = files.next;
+        files.open.insert(id, BufReader::new(file));
+        Ok(id)
+    }
+
+    pub fn close(&self, id: i64) -> Ior {
+        match self.files.borrow_mut().open.remove(&id) {
+            Some(_) => 0,
+            None => IOR_IO,
+        }
+    }
+
+    fn with<T, F>(&self, id: i64, func: F) -> Result<T, Ior>
+    where
+        F: FnOnce(&mut BufReader<File>) -> io::Result<T>,
+    {
+        let mut files = self.files.borrow_mut();
+        let file = files.open.get_mut(&id).ok_or(IOR_IO)?;
+        func(file).map_err(ior)
+    }
+
+    pub fn read(&self, id: i64, buf: &mut [u8]) -> Result<usize, Ior> {
+        self.with(id, |file| {
+            let mut len = 0;
+            while len < buf.len() {
+                match file.read(&mut buf[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            Ok(len)
+        })
+    }
+
+    // Reads up to the end of the line or of `buf`, without the line ending.
+    // The flag is false once the end of the file has been reached.
+    pub fn read_line(&self, id: i64, buf: &mut [u8]) -> Result<(usize, bool), Ior> {
+        self.with(id, |file| {
+            let more = !file.fill_buf()?.is_empty();
+            let mut len = 0;
+            let mut ended = false;
+            while len < buf.len() {
+                let byte = match file.fill_buf()?.first() {
+                    Some(byte) => *byte,
+                    None => break,
+                };
+                file.consume(1);
+                if byte == b'\n' {
+                    ended = true;
+                    break;
+                }
+                buf[len] = byte;
+                len += 1;
+            }
+            if ended && len > 0 && buf[len - 1] == b'\r' {
+                len -= 1;
+            }
+            Ok((len, more))
+        })
+    }
+
+    pub fn write(&self, id: i64, bytes: &[u8]) -> Ior {
+        // Seeking drops what was read ahead, so the bytes land at the file position.
+        self.with(id, |file| {
+            let pos = file.stream_position()?;
+            file.seek(SeekFrom::Start(pos))?;
+            file.get_mut().write_all(bytes)
+        })
+        .err()
+        .unwrap_or(0)
+    }
+
+    pub fn size(&self, id: i64) -> Result<u64, Ior> {
+        self.with(id, |file| Ok(file.get_ref().metadata()?.len()))
+    }
+
+    pub fn position(&self, id: i64) -> Result<u64, Ior> {
+        self.with(id, |file| file.stream_position())
+    }
+
+    pub fn reposition(&self, id: i64, pos: u64) -> Ior {
+        self.with(id, |file| file.seek(SeekFrom::Start(pos)))
+            .err()
+            .unwrap_or(0)
+    }
+}
+
+pub(crate) fn delete(path: &str) -> Ior {
+    fs::remove_file(path).map_err(ior).err().unwrap_or(0)
+}
+
+pub(crate) fn rename(from: &str, to: &str) -> Ior {
+    fs::rename(from, to).map_err(ior).err().unwrap_or(0)
+}
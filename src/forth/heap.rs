@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// Results of ALLOCATE, FREE and RESIZE, using the standard THROW codes.
+pub(crate) const IOR_ALLOCATE: i64 = -59;
+pub(crate) const IOR_FREE: i64 = -60;
+pub(crate) const IOR_RESIZE: i64 = -61;
+
+// Unused bytes kept after every block, so that running off its end never
+// reaches the next one.
+const GAP: i64 = 8;
+
+fn align(addr: i64) -> i64 {
+    (addr + 7) & !7
+}
+
+// Blocks allocated with ALLOCATE, keyed by address. Blocks are placed in an
+// address range above data space that depends on the cell width, so it is
+// passed in; every access must fall inside a single live block.
+#[derive(Clone, Default)]
+pub(crate) struct Heap {
+    blocks: BTreeMap<i64, Vec<u8>>,
+}
+
+impl Heap {
+    pub fn in_use(&self) -> usize {
+        self.blocks.values().map(|b| b.len()).sum()
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = (i64, &[u8])> {
+        self.blocks.iter().map(|(addr, b)| (*addr, b.as_slice()))
+    }
+
+    pub fn size(&self, addr: i64) -> Option<usize> {
+        self.blocks.get(&addr).map(|b| b.len())
+    }
+
+    pub fn insert(&mut self, addr: i64, bytes: Vec<u8>) {
+        self.blocks.insert(addr, bytes);
+    }
+
+    // First fit: the lowest address with room for the block and its gap.
+    pub fn allocate(&mut self, size: usize, space: Range<i64>) -> Option<i64> {
+        let span = (size as i64).max(1);
+        if span > space.end - space.start {
+            return None;
+        }
+        let mut start = space.start;
+        for (addr, block) in &self.blocks {
+            if start + span + GAP <= *addr {
+                break;
+            }
+            start = align(addr + (block.len() as i64).max(1) + GAP);
+        }
+        if span > space.end - start {
+            return None;
+        }
+        self.blocks.insert(start, vec![0; size]);
+        Some(start)
+    }
+
+    pub fn free(&mut self, addr: i64) -> bool {
+        self.blocks.remove(&addr).is_some()
+    }
+
+    // Grows or shrinks a block, moving it if the following block is in the way.
+    pub fn resize(&mut self, addr: i64, size: usize, space: Range<i64>) -> Option<i64> {
+        let end = match self.blocks.range(addr + 1..).next() {
+            Some((next, _)) => next - GAP,
+            None => space.end,
+        };
+        let block = self.blocks.get_mut(&addr)?;
+        if (size as i64).max(1) <= end - addr {
+            block.resize(size, 0);
+            return Some(addr);
+        }
+        let mut bytes = self.blocks.remove(&addr)?;
+        match self.allocate(size, space) {
+            Some(new) => {
+                bytes.resize(size, 0);
+                self.blocks.insert(new, bytes);
+                Some(new)
+            }
+            None => {
+                self.blocks.insert(addr, bytes);
+                None
+            }
+        }
+    }
+
+    fn find(&self, addr: i64, len: usize) -> Option<(i64, usize)> {
+        let (start, block) = self.blocks.range(..=addr).next_back()?;
+        let offset = (addr - start) as usize;
+        if offset.checked_add(len)? <= block.len() {
+            Some((*start, offset))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, addr: i64, len: usize) -> Option<&[u8]> {
+        let (start, offset) = self.find(addr, len)?;
+        Some(&self.blocks[&start][offset..offset + len])
+    }
+
+    pub fn get_mut(&mut self, addr: i64, len: usize) -> Option<&mut [u8]> {
+        let (start, offset) = self.find(addr, len)?;
+        let block = self.blocks.get_mut(&start)?;
+        Some(&mut block[offset..offset + len])
+    }
+}
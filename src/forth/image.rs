@@ -0,0 +1,433 @@
+use super::effect::StackEffect;
+use super::types::*;
+use std::io::{self, Read, Write};
+
+pub(crate) const IMAGE_MAGIC: &[u8; 6] = b"RFORTH";
+pub(crate) const IMAGE_VERSION: u32 = 4;
+
+// Operations without arguments, stored in images by their index here. Only
+// append to this list: reordering it breaks images saved earlier.
+pub(crate) const SIMPLE_OPS: &[ForthOp] = &[
+    ForthOp::Add,
+    ForthOp::Sub,
+    ForthOp::Mul,
+    ForthOp::Div,
+    ForthOp::Mod,
+    ForthOp::DivMod,
+    ForthOp::MulDiv,
+    ForthOp::MulDivMod,
+    ForthOp::Abs,
+    ForthOp::Neg,
+    ForthOp::Add1,
+    ForthOp::Sub1,
+    ForthOp::Add2,
+    ForthOp::Sub2,
+    ForthOp::Mul2,
+    ForthOp::Div2,
+    ForthOp::Dup,
+    ForthOp::Drop,
+    ForthOp::Over,
+    ForthOp::Rot,
+    ForthOp::Swap,
+    ForthOp::Pick,
+    ForthOp::Roll,
+    ForthOp::Print,
+    ForthOp::Emit,
+    ForthOp::Type,
+    ForthOp::Key,
+    ForthOp::Accept,
+    ForthOp::And,
+    ForthOp::Or,
+    ForthOp::Xor,
+    ForthOp::Not,
+    ForthOp::Lt,
+    ForthOp::Gt,
+    ForthOp::Eq,
+    ForthOp::Le,
+    ForthOp::Ge,
+    ForthOp::Ne,
+    ForthOp::Lt0,
+    ForthOp::Eq0,
+    ForthOp::Gt0,
+    ForthOp::Ne0,
+    ForthOp::Invert,
+    ForthOp::True,
+    ForthOp::False,
+    ForthOp::Dup2,
+    ForthOp::Swap2,
+    ForthOp::Drop2,
+    ForthOp::Over2,
+    ForthOp::IfDup,
+    ForthOp::Depth,
+    ForthOp::Lshift,
+    ForthOp::Rshift,
+    ForthOp::Max,
+    ForthOp::Min,
+    ForthOp::Fetch,
+    ForthOp::Store,
+    ForthOp::AddStore,
+    ForthOp::CFetch,
+    ForthOp::CStore,
+    ForthOp::Here,
+    ForthOp::Allot,
+    ForthOp::Comma,
+    ForthOp::CComma,
+    ForthOp::Cells,
+    ForthOp::CellAdd,
+    ForthOp::Chars,
+    ForthOp::CharAdd,
+    ForthOp::Execute,
+    ForthOp::DeferFetch,
+    ForthOp::DeferStore,
+    ForthOp::TestArrow,
+    ForthOp::TestEnd,
+    ForthOp::PrintStack,
+    ForthOp::PrintAt,
+    ForthOp::Dump,
+    ForthOp::Compare,
+    ForthOp::Search,
+    ForthOp::SlashString,
+    ForthOp::DashTrailing,
+    ForthOp::Blank,
+    ForthOp::Move,
+    ForthOp::CMove,
+    ForthOp::CMoveUp,
+    ForthOp::Fill,
+    ForthOp::Erase,
+    ForthOp::SPlus,
+    ForthOp::ReadOnly,
+    ForthOp::WriteOnly,
+    ForthOp::ReadWrite,
+    ForthOp::Bin,
+    ForthOp::CreateFile,
+    ForthOp::OpenFile,
+    ForthOp::CloseFile,
+    ForthOp::ReadFile,
+    ForthOp::ReadLine,
+    ForthOp::WriteFile,
+    ForthOp::WriteLine,
+    ForthOp::FileSize,
+    ForthOp::FilePosition,
+    ForthOp::RepositionFile,
+    ForthOp::DeleteFile,
+    ForthOp::RenameFile,
+    ForthOp::Allocate,
+    ForthOp::Free,
+    ForthOp::Resize,
+    ForthOp::TwoFetch,
+    ForthOp::TwoStore,
+    ForthOp::EnvironmentQuery,
+];
+
+fn corrupt() -> ForthErr {
+    ForthErr::Msg("Corrupt image".to_string())
+}
+
+fn not_an_image() -> ForthErr {
+    ForthErr::Msg("Not an rforth image".to_string())
+}
+
+pub(crate) fn io_err(e: io::Error) -> ForthErr {
+    ForthErr::Msg(format!("Cannot access image: {}", e))
+}
+
+pub(crate) struct ImageWriter<'a> {
+    out: &'a mut dyn Write,
+}
+
+impl<'a> ImageWriter<'a> {
+    pub fn new(out: &'a mut dyn Write) -> ImageWriter<'a> {
+        ImageWriter { out }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> ForthResult<()> {
+        self.out
+            .write_all(bytes)
+            .map_err(|e| ForthErr::Msg(format!("Cannot write image: {}", e)))
+    }
+
+    pub fn header(&mut self) -> ForthResult<()> {
+        self.bytes(IMAGE_MAGIC)?;
+        self.bytes(&IMAGE_VERSION.to_le_bytes())
+    }
+
+    pub fn num(&mut self, n: i64) -> ForthResult<()> {
+        self.bytes(&n.to_le_bytes())
+    }
+
+    pub fn len(&mut self, n: usize) -> ForthResult<()> {
+        self.num(n as i64)
+    }
+
+    pub fn blob(&mut self, bytes: &[u8]) -> ForthResult<()> {
+        self.len(bytes.len())?;
+        self.bytes(bytes)
+    }
+
+    pub fn str(&mut self, s: &str) -> ForthResult<()> {
+        self.blob(s.as_bytes())
+    }
+
+    pub fn effect(&mut self, effect: StackEffect) -> ForthResult<()> {
+        self.len(effect.inputs)?;
+        self.len(effect.outputs)
+    }
+
+    pub fn op(&mut self, op: &ForthOp) -> ForthResult<()> {
+        match op {
+            ForthOp::Variable(name) => self.tagged_str(1, name),
+            ForthOp::Tick(op) => {
+                self.len(2)?;
+                self.op(op)
+            }
+            ForthOp::Defer(name) => self.tagged_str(3, name),
+            ForthOp::Is(name) => self.tagged_str(4, name),
+            ForthOp::ActionOf(name) => self.tagged_str(5, name),
+            ForthOp::UserWord(name) => self.tagged_str(6, name),
+            ForthOp::IfThenElse(n) => self.tagged_len(7, *n),
+            ForthOp::BeginUntil(n) => self.tagged_len(8, *n),
+            ForthOp::BeginWhile(n) => self.tagged_len(9, *n),
+            ForthOp::SaveSystem(path) => self.tagged_str(10, path),
+            ForthOp::TestStart(line) => self.tagged_len(11, *line),
+            ForthOp::Locals(args, total) => {
+                self.tagged_len(12, *args)?;
+                self.len(*total)
+            }
+            ForthOp::Local(n) => self.tagged_len(13, *n),
+            ForthOp::LocalStore(n) => self.tagged_len(14, *n),
+            ForthOp::TwoVariable(name) => self.tagged_str(15, name),
+            ForthOp::Constant(name) => self.tagged_str(16, name),
+            ForthOp::TwoConstant(name) => self.tagged_str(17, name),
+            ForthOp::Value(name) => self.tagged_str(18, name),
+            ForthOp::TwoValue(name) => self.tagged_str(19, name),
+            ForthOp::To(name) => self.tagged_str(20, name),
+            ForthOp::PlusTo(name) => self.tagged_str(21, name),
+            op => match SIMPLE_OPS.iter().position(|o| o == op) {
+                Some(idx) => self.tagged_len(0, idx),
+                None => Err(ForthErr::Msg(format!("Cannot save {} in an image", op))),
+            },
+        }
+    }
+
+    fn tagged_str(&mut self, tag: usize, s: &str) -> ForthResult<()> {
+        self.len(tag)?;
+        self.str(s)
+    }
+
+    fn tagged_len(&mut self, tag: usize, n: usize) -> ForthResult<()> {
+        self.len(tag)?;
+        self.len(n)
+    }
+
+    pub fn body(&mut self, body: &[ForthExp]) -> ForthResult<()> {
+        self.len(body.len())?;
+        for exp in body {
+            match exp {
+                ForthExp::Number(n) => {
+                    self.len(0)?;
+                    self.num(*n)?;
+                }
+                ForthExp::Op(op) => {
+                    self.len(1)?;
+                    self.op(op)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn func(&mut self, func: &ForthFunc) -> ForthResult<()> {
+        match func {
+            ForthFunc::User(body) => {
+                self.len(0)?;
+                self.body(body)
+            }
+            ForthFunc::Variable(name, cells) => {
+                self.tagged_str(1, name)?;
+                self.len(*cells)
+            }
+            ForthFunc::ConstantDef(name, cells) => {
+                self.tagged_str(2, name)?;
+                self.len(*cells)
+            }
+            ForthFunc::Tick(op) => {
+                self.len(3)?;
+                self.op(op)
+            }
+            ForthFunc::Defer(name) => self.tagged_str(4, name),
+            ForthFunc::Is(name) => self.tagged_str(5, name),
+            ForthFunc::ActionOf(name) => self.tagged_str(6, name),
+            ForthFunc::IfThenElse((then, r#else)) => {
+                self.len(7)?;
+                self.body(then)?;
+                match r#else {
+                    Some(body) => {
+                        self.len(1)?;
+                        self.body(body)
+                    }
+                    None => self.len(0),
+                }
+            }
+            ForthFunc::BeginUntil(body) => {
+                self.len(8)?;
+                self.body(body)
+            }
+            ForthFunc::BeginWhile(head, body) => {
+                self.len(9)?;
+                self.body(head)?;
+                self.body(body)
+            }
+            ForthFunc::Constant(n) => {
+                self.len(10)?;
+                self.num(*n)
+            }
+            ForthFunc::TwoConstant(n1, n2) => {
+                self.len(11)?;
+                self.num(*n1)?;
+                self.num(*n2)
+            }
+            _ => Err(ForthErr::Msg(
+                "Only compiled code can be saved in an image".to_string(),
+            )),
+        }
+    }
+}
+
+pub(crate) struct ImageReader<'a> {
+    input: &'a mut dyn Read,
+}
+
+impl<'a> ImageReader<'a> {
+    pub fn new(input: &'a mut dyn Read) -> ImageReader<'a> {
+        ImageReader { input }
+    }
+
+    fn bytes(&mut self, len: usize) -> ForthResult<Vec<u8>> {
+        let mut buf = vec![];
+        (&mut *self.input)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .map_err(|e| ForthErr::Msg(format!("Cannot read image: {}", e)))?;
+        if buf.len() != len {
+            return Err(corrupt());
+        }
+        Ok(buf)
+    }
+
+    pub fn header(&mut self) -> ForthResult<()> {
+        let magic = self.bytes(IMAGE_MAGIC.len()).map_err(|_| not_an_image())?;
+        if magic != IMAGE_MAGIC {
+            return Err(not_an_image());
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(&self.bytes(4)?);
+        let version = u32::from_le_bytes(version);
+        if version != IMAGE_VERSION {
+            return Err(ForthErr::Msg(format!(
+                "Incompatible image version {} (expected {})",
+                version, IMAGE_VERSION
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn num(&mut self) -> ForthResult<i64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&self.bytes(8)?);
+        Ok(i64::from_le_bytes(buf))
+    }
+
+    pub fn len(&mut self) -> ForthResult<usize> {
+        let n = self.num()?;
+        if n < 0 {
+            return Err(corrupt());
+        }
+        Ok(n as usize)
+    }
+
+    pub fn blob(&mut self) -> ForthResult<Vec<u8>> {
+        let len = self.len()?;
+        self.bytes(len)
+    }
+
+    pub fn str(&mut self) -> ForthResult<String> {
+        String::from_utf8(self.blob()?).map_err(|_| corrupt())
+    }
+
+    pub fn effect(&mut self) -> ForthResult<StackEffect> {
+        Ok(StackEffect {
+            inputs: self.len()?,
+            outputs: self.len()?,
+        })
+    }
+
+    pub fn op(&mut self) -> ForthResult<ForthOp> {
+        let op = match self.len()? {
+            0 => SIMPLE_OPS.get(self.len()?).ok_or_else(corrupt)?.clone(),
+            1 => ForthOp::Variable(self.str()?),
+            2 => ForthOp::Tick(Box::new(self.op()?)),
+            3 => ForthOp::Defer(self.str()?),
+            4 => ForthOp::Is(self.str()?),
+            5 => ForthOp::ActionOf(self.str()?),
+            6 => ForthOp::UserWord(self.str()?),
+            7 => ForthOp::IfThenElse(self.len()?),
+            8 => ForthOp::BeginUntil(self.len()?),
+            9 => ForthOp::BeginWhile(self.len()?),
+            10 => ForthOp::SaveSystem(self.str()?),
+            11 => ForthOp::TestStart(self.len()?),
+            12 => ForthOp::Locals(self.len()?, self.len()?),
+            13 => ForthOp::Local(self.len()?),
+            14 => ForthOp::LocalStore(self.len()?),
+            15 => ForthOp::TwoVariable(self.str()?),
+            16 => ForthOp::Constant(self.str()?),
+            17 => ForthOp::TwoConstant(self.str()?),
+            18 => ForthOp::Value(self.str()?),
+            19 => ForthOp::TwoValue(self.str()?),
+            20 => ForthOp::To(self.str()?),
+            21 => ForthOp::PlusTo(self.str()?),
+            _ => return Err(corrupt()),
+        };
+        Ok(op)
+    }
+
+    pub fn body(&mut self) -> ForthResult<Vec<ForthExp>> {
+        let len = self.len()?;
+        let mut body = vec![];
+        for _ in 0..len {
+            let exp = match self.len()? {
+                0 => ForthExp::Number(self.num()?),
+                1 => ForthExp::Op(self.op()?),
+                _ => return Err(corrupt()),
+            };
+            body.push(exp);
+        }
+        Ok(body)
+    }
+
+    pub fn func(&mut self) -> ForthResult<ForthFunc> {
+        let func = match self.len()? {
+            0 => ForthFunc::User(self.body()?.into()),
+            1 => ForthFunc::Variable(self.str()?, self.len()?),
+            2 => ForthFunc::ConstantDef(self.str()?, self.len()?),
+            3 => ForthFunc::Tick(self.op()?),
+            4 => ForthFunc::Defer(self.str()?),
+            5 => ForthFunc::Is(self.str()?),
+            6 => ForthFunc::ActionOf(self.str()?),
+            7 => {
+                let then = self.body()?;
+                let r#else = match self.len()? {
+                    0 => None,
+                    1 => Some(self.body()?.into()),
+                    _ => return Err(corrupt()),
+                };
+                ForthFunc::IfThenElse((then.into(), r#else))
+            }
+            8 => ForthFunc::BeginUntil(self.body()?.into()),
+            9 => ForthFunc::BeginWhile(self.body()?.into(), self.body()?.into()),
+            10 => ForthFunc::Constant(self.num()?),
+            11 => ForthFunc::TwoConstant(self.num()?, self.num()?),
+            _ => return Err(corrupt()),
+        };
+        Ok(func)
+    }
+}
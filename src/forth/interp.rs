@@ -1,33 +1,69 @@
-use super::parser::ForthParser;
+use super::debug::{StackTracer, TraceStep, Tracer};
+use super::effect::{EffectChecker, StackEffect};
+use super::file::{self, FileTable};
+use super::heap::{self, Heap};
+use super::image::{self, ImageReader, ImageWriter};
+use super::parser::{self, Definition, ForthParser, ParserResult};
 use super::types::*;
-use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::{max, min, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
 
 const TRUE: i64 = -1;
 const FALSE: i64 = 0;
+const MAX_DATA_SPACE: usize = 1 << 24;
+// Addresses above data space that ALLOCATE hands out.
+const MAX_HEAP_SPACE: i64 = 1 << 28;
+const DEADLINE_CHECK_INTERVAL: u64 = 256;
+
+// 16-bit addresses above 0x7fff are negative, so data space and the heap
+// share the positive half there.
+fn data_space_limit(cell_width: CellWidth) -> usize {
+    match cell_width {
+        CellWidth::Bits16 => 1 << 14,
+        _ => MAX_DATA_SPACE,
+    }
+}
+
+fn heap_space(cell_width: CellWidth) -> Range<i64> {
+    match cell_width {
+        CellWidth::Bits16 => 1 << 14..1 << 15,
+        _ => MAX_DATA_SPACE as i64..MAX_HEAP_SPACE,
+    }
+}
 
 macro_rules! n_ary_op {
     ($n: expr, $func: expr) => {
         ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
             let mut x: [i64; $n] = [0; $n];
-            for i in 0..$n {
-                x[i] = interp.pop_num()?;
+            for v in x.iter_mut() {
+                *v = interp.pop_num()?;
             }
-            interp.push(ForthExp::Number($func(x)));
-            return Ok(());
-        });
+            interp.push_num($func(x))?;
+            Ok(())
+        })
     };
 }
 
+// Puts back cells just popped, which always fit.
 macro_rules! restore_stack {
+    ($a: expr, $interp: ident) => {
+        $interp.stack.push($a);
+    };
     ($a: expr, $b: expr, $interp: ident) => {
-        $interp.push(ForthExp::Number($a));
-        $interp.push(ForthExp::Number($b));
+        $interp.stack.push($a);
+        $interp.stack.push($b);
     };
     ($a: expr, $b: expr, $c:expr, $interp: ident) => {
-        $interp.push(ForthExp::Number($a));
-        $interp.push(ForthExp::Number($b));
-        $interp.push(ForthExp::Number($c));
+        $interp.stack.push($a);
+        $interp.stack.push($b);
+        $interp.stack.push($c);
     };
 }
 
@@ -35,27 +71,182 @@ macro_rules! checked_div {
     ($n: expr, $func: expr) => {
         ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
             let mut x: [i64; $n] = [0; $n];
-            for i in 0..$n {
-                x[i] = interp.pop_num()?;
+            for v in x.iter_mut() {
+                *v = interp.pop_num()?;
             }
             if x[0] == 0 {
-                for i in (0..$n).rev() {
-                    interp.push(ForthExp::Number(x[i]));
+                for v in x.iter().rev() {
+                    interp.stack.push(*v);
                 }
                 return Err(ForthErr::Msg("Division by zero".to_string()));
             }
-            interp.push(ForthExp::Number($func(x)));
-            return Ok(());
-        });
+            interp.push_num($func(x))?;
+            Ok(())
+        })
+    };
+}
+
+// Pops the arguments of a word that works on data space, putting them back
+// if it fails.
+macro_rules! mem_op {
+    ($n: expr, $func: expr) => {
+        ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+            let mut x: [i64; $n] = [0; $n];
+            for v in x.iter_mut() {
+                *v = interp.pop_num()?;
+            }
+            if let Err(e) = $func(interp, x) {
+                for v in x.iter().rev() {
+                    interp.stack.push(*v);
+                }
+                return Err(e);
+            }
+            Ok(())
+        })
     };
 }
 
+// */ and */MOD keep the intermediate product at double width.
+fn mul_div(a: i64, b: i64, c: i64) -> (i64, i64) {
+    let prod = a as i128 * b as i128;
+    ((prod % c as i128) as i64, (prod / c as i128) as i64)
+}
+
+// Compiled code held by a word, if any.
+fn func_bodies(func: &ForthFunc) -> Vec<&[ForthExp]> {
+    match func {
+        ForthFunc::User(body) | ForthFunc::BeginUntil(body) => vec![body],
+        ForthFunc::BeginWhile(head, body) => vec![head, body],
+        ForthFunc::IfThenElse((then, Some(r#else))) => vec![then, r#else],
+        ForthFunc::IfThenElse((then, None)) => vec![then],
+        _ => vec![],
+    }
+}
+
+// Calls `f` with every op in `code` and in the control structures compiled
+// along with it.
+fn visit<'a>(res: &'a ParserResult, code: &'a [ForthExp], f: &mut impl FnMut(&'a ForthOp)) {
+    for exp in code {
+        if let ForthExp::Op(op) = exp {
+            f(op);
+            if let Some(func) = res.new_words.get(op) {
+                for body in func_bodies(func) {
+                    visit(res, body, f);
+                }
+            }
+        }
+    }
+}
+
+fn word_name(op: &ForthOp) -> String {
+    match op {
+        ForthOp::UserWord(name) => name.clone(),
+        ForthOp::Tick(op) => format!("' {}", word_name(op)),
+        ForthOp::Variable(name) => format!("variable {}", name),
+        ForthOp::TwoVariable(name) => format!("2variable {}", name),
+        ForthOp::Constant(name) => format!("constant {}", name),
+        ForthOp::TwoConstant(name) => format!("2constant {}", name),
+        ForthOp::Value(name) => format!("value {}", name),
+        ForthOp::TwoValue(name) => format!("2value {}", name),
+        ForthOp::To(name) => format!("to {}", name),
+        ForthOp::PlusTo(name) => format!("+to {}", name),
+        ForthOp::Defer(name) => format!("defer {}", name),
+        ForthOp::Is(name) => format!("is {}", name),
+        ForthOp::ActionOf(name) => format!("action-of {}", name),
+        ForthOp::SaveSystem(path) => format!("save-system {}", path),
+        ForthOp::SQuote(text) => format!("s\" {}\"", text),
+        ForthOp::TestStart(_) => "t{".to_string(),
+        ForthOp::TestArrow => "->".to_string(),
+        ForthOp::TestEnd => "}t".to_string(),
+        ForthOp::IfThenElse(_) => "if".to_string(),
+        ForthOp::BeginUntil(_) | ForthOp::BeginWhile(_) => "begin".to_string(),
+        ForthOp::Locals(..) => "{:".to_string(),
+        ForthOp::Local(n) => format!("local {}", n),
+        ForthOp::LocalStore(n) => format!("to local {}", n),
+        op => match parser::word_of(op) {
+            Some(word) => word.to_string(),
+            None => op.to_string().to_lowercase(),
+        },
+    }
+}
+
+// A body being run by `eval`, what happens when it ends, and the traced
+// step that entered it.
+struct Frame {
+    body: Body,
+    pc: usize,
+    kind: FrameKind,
+    step: Option<TraceStep>,
+}
+
+enum FrameKind {
+    // A colon definition, with where its caller's locals start.
+    Call(usize),
+    Branch,
+    Until,
+    // A BEGIN WHILE loop with the part not running now, and whether the
+    // running one is the head.
+    While(Body, bool),
+}
+
+// An open `T{` and the results collected by its `->`.
+#[derive(Clone)]
+struct TestMark {
+    line: usize,
+    depth: usize,
+    actual: Option<Vec<i64>>,
+}
+
 #[derive(Clone)]
 pub struct ForthInterp {
-    pub words: HashMap<ForthOp, ForthFunc>,
-    pub stack: Vec<ForthExp>,
-    pub variables: HashMap<String, i64>,
-    pub parser: ForthParser,
+    words: HashMap<ForthOp, ForthFunc>,
+    stack: Vec<i64>,
+    variables: HashMap<String, i64>,
+    // Address and number of cells of every VALUE and 2VALUE.
+    values: HashMap<String, (i64, usize)>,
+    memory: Vec<u8>,
+    cell_width: CellWidth,
+    xts: Vec<ForthOp>,
+    deferred: HashMap<String, i64>,
+    parser: ForthParser,
+    transactional: bool,
+    limits: Limits,
+    steps: u64,
+    call_depth: usize,
+    // Cells of the locals of every running definition; `frame` is where
+    // the innermost one's start.
+    locals: Vec<i64>,
+    frame: usize,
+    eval_nesting: usize,
+    deadline: Option<Instant>,
+    capabilities: Capabilities,
+    cancel: CancelHandle,
+    tracer: Option<Rc<RefCell<dyn Tracer>>>,
+    effects: HashMap<String, StackEffect>,
+    strict_effects: bool,
+    legacy_invert: bool,
+    files: FileTable,
+    heap: Heap,
+    leak_report: bool,
+    warnings: Vec<String>,
+    test_marks: Vec<TestMark>,
+    test_results: TestResults,
+}
+
+impl Default for ForthInterp {
+    fn default() -> ForthInterp {
+        ForthInterp::new()
+    }
+}
+
+impl Drop for ForthInterp {
+    fn drop(&mut self) {
+        if self.leak_report {
+            if let Some(report) = self.leak_report() {
+                eprintln!("rforth: {}", report);
+            }
+        }
+    }
 }
 
 impl ForthInterp {
@@ -64,29 +255,712 @@ impl ForthInterp {
             words: HashMap::new(),
             stack: vec![],
             variables: HashMap::new(),
+            values: HashMap::new(),
+            memory: vec![],
+            cell_width: CellWidth::Bits64,
+            xts: vec![],
+            deferred: HashMap::new(),
             parser: ForthParser::new(),
+            transactional: false,
+            limits: Limits::default(),
+            steps: 0,
+            call_depth: 0,
+            locals: vec![],
+            frame: 0,
+            eval_nesting: 0,
+            deadline: None,
+            capabilities: Capabilities::all(),
+            cancel: CancelHandle::default(),
+            tracer: None,
+            effects: HashMap::new(),
+            strict_effects: false,
+            legacy_invert: false,
+            files: FileTable::default(),
+            heap: Heap::default(),
+            leak_report: false,
+            warnings: vec![],
+            test_marks: vec![],
+            test_results: TestResults::default(),
+        }
+    }
+    pub fn with_cell_width(cell_width: CellWidth) -> ForthInterp {
+        let mut interp = ForthInterp::new();
+        interp.cell_width = cell_width;
+        interp
+    }
+    pub fn cell_width(&self) -> CellWidth {
+        self.cell_width
+    }
+    // In transactional mode a failing eval_str leaves the stack, data space
+    // and dictionary exactly as they were before the call.
+    pub fn set_transactional(&mut self, transactional: bool) {
+        self.transactional = transactional;
+    }
+    pub fn transactional(&self) -> bool {
+        self.transactional
+    }
+    // With the leak report on, an interpreter that is dropped while heap
+    // blocks are still allocated lists them on stderr.
+    pub fn set_leak_report(&mut self, leak_report: bool) {
+        self.leak_report = leak_report;
+    }
+    // The heap blocks that have not been freed, or None if there are none.
+    pub fn leak_report(&self) -> Option<String> {
+        let blocks: Vec<String> = self
+            .heap
+            .blocks()
+            .map(|(addr, bytes)| format!("{} ({} bytes)", addr, bytes.len()))
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{} heap blocks ({} bytes) were never freed: {}",
+            blocks.len(),
+            self.heap.in_use(),
+            blocks.join(", ")
+        ))
+    }
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    pub fn set_tracer(&mut self, tracer: Option<Rc<RefCell<dyn Tracer>>>) {
+        self.tracer = tracer;
+    }
+    // Logs every executed word with the stack around it to stderr.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.tracer = if trace {
+            Some(Rc::new(RefCell::new(StackTracer::stderr())))
+        } else {
+            None
+        };
+    }
+    // With strict effects a definition whose stack effect doesn't match its
+    // `( -- )` comment, or whose IF branches are unbalanced, is rejected
+    // instead of producing a warning.
+    pub fn set_strict_effects(&mut self, strict: bool) {
+        self.strict_effects = strict;
+    }
+    pub fn strict_effects(&self) -> bool {
+        self.strict_effects
+    }
+    // Scripts written before INVERT became the bitwise complement can keep the
+    // old behaviour, where INVERT returned a flag and NOT inverted the bits.
+    pub fn set_legacy_invert(&mut self, legacy: bool) {
+        self.legacy_invert = legacy;
+    }
+    pub fn legacy_invert(&self) -> bool {
+        self.legacy_invert
+    }
+    // Outcome of the `T{ ... -> ... }T` tests run so far.
+    pub fn test_results(&self) -> &TestResults {
+        &self.test_results
+    }
+    pub fn take_test_results(&mut self) -> TestResults {
+        self.test_marks.clear();
+        std::mem::take(&mut self.test_results)
+    }
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+    // Declared or inferred stack effect of a word, if it can be known statically.
+    pub fn stack_effect(&self, name: &str) -> Option<StackEffect> {
+        let name = name.to_ascii_lowercase();
+        let new_words = HashMap::new();
+        let mut checker = EffectChecker::new(
+            &new_words,
+            &self.words,
+            &self.effects,
+            &self.variables,
+            &self.values,
+            &self.deferred,
+        );
+        checker.effect_of(&name)
+    }
+    fn check_effects(
+        &mut self,
+        name: &str,
+        body: &Body,
+        effect: Option<StackEffect>,
+    ) -> ForthResult<()> {
+        let new_words = HashMap::from([(
+            ForthOp::UserWord(name.to_string()),
+            ForthFunc::User(body.clone()),
+        )]);
+        let previous = match effect {
+            Some(effect) => self.effects.insert(name.to_string(), effect),
+            None => self.effects.remove(name),
+        };
+        let mut checker = EffectChecker::new(
+            &new_words,
+            &self.words,
+            &self.effects,
+            &self.variables,
+            &self.values,
+            &self.deferred,
+        );
+        checker.check(name, effect);
+        let issues = checker.issues;
+        if self.strict_effects && !issues.is_empty() {
+            match previous {
+                Some(effect) => self.effects.insert(name.to_string(), effect),
+                None => self.effects.remove(name),
+            };
+            return Err(ForthErr::Msg(issues.join("\n")));
+        }
+        self.warnings.extend(issues);
+        Ok(())
+    }
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+    fn count_step(&mut self) -> ForthResult<()> {
+        if self.cancel.take() {
+            return Err(ForthErr::Interrupted);
+        }
+        self.steps += 1;
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(ForthErr::LimitExceeded(Limit::Steps));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(ForthErr::LimitExceeded(Limit::Timeout));
+            }
+        }
+        Ok(())
+    }
+    // Rejects references to words that don't exist when the code is compiled
+    // rather than when it runs. A colon definition can only use the words
+    // defined before it and itself, so forward references go through DEFER.
+    fn check_names<'a>(
+        &self,
+        res: &'a ParserResult,
+        code: &'a [ForthExp],
+        name: Option<&'a str>,
+    ) -> ForthResult<()> {
+        let mut created: HashSet<&str> = name.into_iter().collect();
+        let mut names = vec![];
+        visit(res, code, &mut |op| match op {
+            ForthOp::Variable(name)
+            | ForthOp::TwoVariable(name)
+            | ForthOp::Constant(name)
+            | ForthOp::TwoConstant(name)
+            | ForthOp::Value(name)
+            | ForthOp::TwoValue(name)
+            | ForthOp::Defer(name) => {
+                created.insert(name);
+            }
+            ForthOp::UserWord(name)
+            | ForthOp::To(name)
+            | ForthOp::PlusTo(name)
+            | ForthOp::Is(name)
+            | ForthOp::ActionOf(name) => names.push(name.as_str()),
+            ForthOp::Tick(op) => {
+                if let ForthOp::UserWord(name) = &**op {
+                    names.push(name);
+                }
+            }
+            _ => {}
+        });
+        let defined = |name: &str| {
+            created.contains(name)
+                || self.variables.contains_key(name)
+                || self.values.contains_key(name)
+                || self.deferred.contains_key(name)
+                || self
+                    .words
+                    .contains_key(&ForthOp::UserWord(name.to_string()))
+        };
+        match names.into_iter().find(|name| !defined(name)) {
+            Some(name) => Err(ForthErr::Msg(format!("{} ?", name))),
+            None => Ok(()),
+        }
+    }
+    fn check_words(&self, new_words: usize) -> ForthResult<()> {
+        if let Some(max) = self.limits.max_words {
+            let defined = self
+                .words
+                .keys()
+                .filter(|op| matches!(op, ForthOp::UserWord(_)))
+                .count();
+            let named = self.variables.len() + self.values.len() + self.deferred.len();
+            if defined + named + new_words > max {
+                return Err(ForthErr::LimitExceeded(Limit::Words));
+            }
+        }
+        Ok(())
+    }
+    fn call(&mut self) -> ForthResult<FrameKind> {
+        if let Some(max) = self.limits.max_return_depth {
+            if self.call_depth >= max {
+                return Err(ForthErr::LimitExceeded(Limit::ReturnDepth));
+            }
+        }
+        self.call_depth += 1;
+        let caller = std::mem::replace(&mut self.frame, self.locals.len());
+        Ok(FrameKind::Call(caller))
+    }
+    fn local(&mut self, n: usize) -> ForthResult<&mut i64> {
+        self.locals.get_mut(self.frame + n).ok_or(ForthErr::Msg(
+            "Local used before its declaration ran".to_string(),
+        ))
+    }
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+    fn required_capability(&self, op: &ForthOp) -> Option<Capability> {
+        match op {
+            ForthOp::Key | ForthOp::Accept => Some(Capability::ConsoleInput),
+            ForthOp::SaveSystem(_)
+            | ForthOp::CreateFile
+            | ForthOp::OpenFile
+            | ForthOp::CloseFile
+            | ForthOp::ReadFile
+            | ForthOp::ReadLine
+            | ForthOp::WriteFile
+            | ForthOp::WriteLine
+            | ForthOp::FileSize
+            | ForthOp::FilePosition
+            | ForthOp::RepositionFile
+            | ForthOp::DeleteFile
+            | ForthOp::RenameFile => Some(Capability::FileAccess),
+            ForthOp::EnvironmentQuery => Some(Capability::Environment),
+            ForthOp::Tick(op) => self.required_capability(op),
+            ForthOp::UserWord(_) => match self.words.get(op) {
+                Some(ForthFunc::Host(_)) => Some(Capability::HostNatives),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    fn check_capabilities<'a, I>(&self, code: I) -> ForthResult<()>
+    where
+        I: IntoIterator<Item = &'a ForthExp>,
+    {
+        for exp in code {
+            if let ForthExp::Op(op) = exp {
+                self.check_allowed(op)?;
+            }
+        }
+        Ok(())
+    }
+    fn check_allowed(&self, op: &ForthOp) -> ForthResult<()> {
+        match self.required_capability(op) {
+            Some(cap) if !self.capabilities.allows(cap) => {
+                Err(ForthErr::Forbidden(word_name(op), cap))
+            }
+            _ => Ok(()),
+        }
+    }
+    fn warn_legacy(&mut self, code: &[&ForthExp]) {
+        let mut used = (false, false);
+        for exp in code {
+            match exp {
+                ForthExp::Op(ForthOp::Invert) => used.0 = true,
+                ForthExp::Op(ForthOp::Not) => used.1 = true,
+                ForthExp::Op(ForthOp::Tick(op)) => match **op {
+                    ForthOp::Invert => used.0 = true,
+                    ForthOp::Not => used.1 = true,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        if used.0 {
+            self.warnings.push(
+                "legacy INVERT returns a flag: use 0= for that, INVERT is bitwise by default"
+                    .to_string(),
+            );
         }
+        if used.1 {
+            self.warnings.push(
+                "legacy NOT inverts all bits: use INVERT for that, NOT is 0= by default"
+                    .to_string(),
+            );
+        }
+    }
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
     }
-    pub fn pop_num(&mut self) -> Result<i64, ForthErr> {
-        let exp = self
-            .stack
+    pub fn clear_stack(&mut self) {
+        self.stack.clear();
+    }
+    pub fn pop_num(&mut self) -> ForthResult<i64> {
+        self.stack
             .pop()
-            .ok_or(ForthErr::Msg("Empty stack".to_string()))?;
-        match exp {
-            ForthExp::Number(num) => Ok(num),
-            _ => Err(ForthErr::Msg("expected a number".to_string())),
+            .ok_or(ForthErr::Msg("Empty stack".to_string()))
+    }
+    pub fn push_num(&mut self, num: i64) -> ForthResult<()> {
+        if let Some(max) = self.limits.max_stack_depth {
+            if self.stack.len() >= max {
+                return Err(ForthErr::LimitExceeded(Limit::StackDepth));
+            }
+        }
+        let num = self.cell_width.wrap(num);
+        self.stack.push(num);
+        Ok(())
+    }
+    fn push_nums(&mut self, nums: &[i64]) -> ForthResult<()> {
+        for num in nums {
+            self.push_num(*num)?;
+        }
+        Ok(())
+    }
+    pub fn pop_bool(&mut self) -> ForthResult<bool> {
+        Ok(self.pop_num()? != FALSE)
+    }
+    pub fn push_bool(&mut self, flag: bool) -> ForthResult<()> {
+        self.push_num(if flag { TRUE } else { FALSE })
+    }
+    pub fn pop_usize(&mut self) -> ForthResult<usize> {
+        let num = self.pop_num()?;
+        if num < 0 {
+            restore_stack!(num, self);
+            return Err(ForthErr::Msg(format!(
+                "expected a non-negative number, got {}",
+                num
+            )));
+        }
+        Ok(num as usize)
+    }
+    // Pops a ( c-addr u ) pair and decodes the bytes it points to.
+    pub fn pop_str(&mut self) -> ForthResult<String> {
+        let len = self.pop_num()?;
+        let addr = self.pop_num()?;
+        match self.mem(addr, len) {
+            Ok(bytes) => Ok(String::from_utf8_lossy(bytes).into_owned()),
+            Err(_) => {
+                restore_stack!(addr, len, self);
+                Err(ForthErr::Msg(format!("Invalid string at {}", addr)))
+            }
+        }
+    }
+    pub fn register_native<F>(&mut self, name: &str, func: F)
+    where
+        F: Fn(&mut ForthInterp) -> ForthResult<()> + 'static,
+    {
+        let name = name.to_ascii_lowercase();
+        self.variables.remove(&name);
+        self.deferred.remove(&name);
+        self.words
+            .insert(ForthOp::UserWord(name), ForthFunc::Host(Rc::new(func)));
+    }
+    // Shifting by the cell width or more clears the cell, as on a real target.
+    fn shift_count(&self, n: i64) -> Option<u32> {
+        if n >= 0 && n < self.cell_width.bits() as i64 {
+            Some(n as u32)
+        } else {
+            None
+        }
+    }
+    // The bytes at ( addr len ), in data space or in an allocated block.
+    fn mem(&self, addr: i64, len: i64) -> ForthResult<&[u8]> {
+        if len < 0 {
+            return Err(ForthErr::Msg(format!("Invalid length {}", len)));
+        }
+        let bytes = if addr >= heap_space(self.cell_width).start {
+            self.heap.get(addr, len as usize)
+        } else if addr >= 0 {
+            self.memory
+                .get(addr as usize..)
+                .and_then(|m| m.get(..len as usize))
+        } else {
+            None
+        };
+        bytes.ok_or_else(|| ForthErr::Msg(format!("Invalid memory address {}", addr)))
+    }
+    fn mem_mut(&mut self, addr: i64, len: i64) -> ForthResult<&mut [u8]> {
+        if len < 0 {
+            return Err(ForthErr::Msg(format!("Invalid length {}", len)));
+        }
+        let bytes = if addr >= heap_space(self.cell_width).start {
+            self.heap.get_mut(addr, len as usize)
+        } else if addr >= 0 {
+            self.memory
+                .get_mut(addr as usize..)
+                .and_then(|m| m.get_mut(..len as usize))
+        } else {
+            None
+        };
+        bytes.ok_or_else(|| ForthErr::Msg(format!("Invalid memory address {}", addr)))
+    }
+    // The stack as `.S` shows it: depth first, top of stack last.
+    pub fn format_stack(&self) -> String {
+        let mut res = format!("<{}>", self.stack.len());
+        for num in &self.stack {
+            res.push_str(&format!(" {}", num));
+        }
+        res
+    }
+    // DUMP output: 16 bytes a line with their address and printable characters.
+    pub fn hex_dump(&self, addr: i64, len: usize) -> ForthResult<String> {
+        let mut res = String::new();
+        for (i, line) in self.mem(addr, len as i64)?.chunks(16).enumerate() {
+            res.push_str(&format!("{:08x}:", addr + i as i64 * 16));
+            for b in line {
+                res.push_str(&format!(" {:02x}", b));
+            }
+            res.push_str(&" ".repeat(3 * (16 - line.len()) + 2));
+            for &b in line {
+                res.push(if (0x20..0x7f).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                });
+            }
+            res.push('\n');
+        }
+        Ok(res)
+    }
+    pub fn variable(&self, name: &str) -> Option<i64> {
+        let addr = self.variables.get(&name.to_ascii_lowercase())?;
+        self.fetch(*addr).ok()
+    }
+    pub fn variables(&self) -> Vec<(String, i64)> {
+        let mut res: Vec<(String, i64)> = self
+            .variables
+            .iter()
+            .filter_map(|(name, addr)| Some((name.clone(), self.fetch(*addr).ok()?)))
+            .collect();
+        res.sort();
+        res
+    }
+    pub fn set_variable(&mut self, name: &str, num: i64) -> ForthResult<()> {
+        let addr = *self
+            .variables
+            .get(&name.to_ascii_lowercase())
+            .ok_or(ForthErr::Msg(format!("Not defined variable {}", name)))?;
+        self.store(addr, num)
+    }
+    pub fn fetch(&self, addr: i64) -> ForthResult<i64> {
+        let bytes = self.cell_width.bytes();
+        let mut buf = [0u8; 8];
+        buf[..bytes].copy_from_slice(self.mem(addr, bytes as i64)?);
+        Ok(self.cell_width.wrap(i64::from_le_bytes(buf)))
+    }
+    pub fn store(&mut self, addr: i64, num: i64) -> ForthResult<()> {
+        let bytes = self.cell_width.bytes();
+        self.mem_mut(addr, bytes as i64)?
+            .copy_from_slice(&num.to_le_bytes()[..bytes]);
+        Ok(())
+    }
+    // A cell pair ( x1 x2 ) as 2! leaves it: x2 at addr and x1 in the next cell.
+    pub fn fetch2(&self, addr: i64) -> ForthResult<(i64, i64)> {
+        let cell = self.cell_width.bytes() as i64;
+        self.mem(addr, 2 * cell)?;
+        Ok((self.fetch(addr + cell)?, self.fetch(addr)?))
+    }
+    pub fn store2(&mut self, addr: i64, x1: i64, x2: i64) -> ForthResult<()> {
+        let cell = self.cell_width.bytes() as i64;
+        self.mem(addr, 2 * cell)?;
+        self.store(addr, x2)?;
+        self.store(addr + cell, x1)
+    }
+    pub fn fetch_char(&self, addr: i64) -> ForthResult<i64> {
+        Ok(self.mem(addr, 1)?[0] as i64)
+    }
+    pub fn store_char(&mut self, addr: i64, num: i64) -> ForthResult<()> {
+        self.mem_mut(addr, 1)?[0] = num as u8;
+        Ok(())
+    }
+    // A file name held in data space.
+    fn mem_path(&self, addr: i64, len: i64) -> ForthResult<String> {
+        Ok(String::from_utf8_lossy(self.mem(addr, len)?).into_owned())
+    }
+    // CREATE-FILE and OPEN-FILE ( c-addr u fam -- fileid ior ).
+    fn open_file(&mut self, x: [i64; 3], create: bool) -> ForthResult<()> {
+        let path = self.mem_path(x[2], x[1])?;
+        match self.files.open(&path, x[0], create) {
+            Ok(id) => self.push_nums(&[id, 0])?,
+            Err(ior) => self.push_nums(&[0, ior])?,
+        }
+        Ok(())
+    }
+    // A file size or position as ( ud ior ).
+    fn push_offset(&mut self, res: Result<u64, file::Ior>) -> ForthResult<()> {
+        match res {
+            Ok(n) => self.push_nums(&[n as i64, 0, 0])?,
+            Err(ior) => self.push_nums(&[0, 0, ior])?,
+        }
+        Ok(())
+    }
+    // The answer to an ENVIRONMENT? query, if it has one.
+    fn environment(&self, query: &str) -> Option<i64> {
+        let max_n = (self.cell_width.unsigned(-1) >> 1) as i64;
+        let limit = |max: Option<usize>| max.map(|n| (n as i64).min(max_n));
+        match query.to_ascii_lowercase().as_str() {
+            "address-unit-bits" => Some(8),
+            "max-char" => Some(255),
+            "max-n" => Some(max_n),
+            "max-u" => Some(-1),
+            // `/` and MOD round towards zero.
+            "floored" => Some(0),
+            "stack-cells" => limit(self.limits.max_stack_depth),
+            "return-stack-cells" => limit(self.limits.max_return_depth),
+            _ => None,
+        }
+    }
+    fn fill(&mut self, addr: i64, len: i64, byte: u8) -> ForthResult<()> {
+        self.mem_mut(addr, len)?.fill(byte);
+        Ok(())
+    }
+    // Appends ( a2 u2 ) to ( a1 u1 ) in new data space. A string that already
+    // ends at HERE, such as the result of an earlier S+, grows in place.
+    fn join(&mut self, a1: i64, u1: i64, a2: i64, u2: i64) -> ForthResult<(i64, i64)> {
+        let first = self.mem(a1, u1)?.to_vec();
+        let second = self.mem(a2, u2)?.to_vec();
+        let len = u1 + u2;
+        if a1 + u1 == self.memory.len() as i64 {
+            let end = self.allot(u2)?;
+            self.mem_mut(end, u2)?.copy_from_slice(&second);
+            return Ok((a1, len));
+        }
+        let addr = self.allot(len)?;
+        self.mem_mut(addr, u1)?.copy_from_slice(&first);
+        self.mem_mut(addr + u1, u2)?.copy_from_slice(&second);
+        Ok((addr, len))
+    }
+    // Heap blocks count against the data space limit on their own, and
+    // running out is reported to the program as an ior rather than an error.
+    fn heap_room(&self, size: i64, freed: usize) -> bool {
+        let in_use = (self.heap.in_use() - freed) as i64;
+        match self.limits.max_data_space {
+            Some(max) => size <= max as i64 - in_use,
+            None => true,
+        }
+    }
+    fn allocate(&mut self, size: i64) -> Option<i64> {
+        if size < 0 || !self.heap_room(size, 0) {
+            return None;
+        }
+        let space = heap_space(self.cell_width);
+        self.heap.allocate(size as usize, space)
+    }
+    fn resize(&mut self, addr: i64, size: i64) -> Option<i64> {
+        let old = self.heap.size(addr)?;
+        if size < 0 || !self.heap_room(size, old) {
+            return None;
+        }
+        let space = heap_space(self.cell_width);
+        self.heap.resize(addr, size as usize, space)
+    }
+    pub fn allot(&mut self, n: i64) -> ForthResult<i64> {
+        let here = self.memory.len() as i64;
+        let new_len = here.saturating_add(n);
+        if new_len < 0 || new_len as usize > data_space_limit(self.cell_width) {
+            return Err(ForthErr::Msg("Data space overflow".to_string()));
+        }
+        if let Some(max) = self.limits.max_data_space {
+            if n > 0 && new_len as usize > max {
+                return Err(ForthErr::LimitExceeded(Limit::DataSpace));
+            }
+        }
+        self.memory.resize(new_len as usize, 0);
+        Ok(here)
+    }
+    // Execution tokens are 1-based indexes into `xts`, so 0 is never a valid token.
+    pub(crate) fn xt_of(&mut self, op: ForthOp) -> ForthResult<i64> {
+        if let ForthOp::UserWord(name) = &op {
+            if !(self.variables.contains_key(name)
+                || self.values.contains_key(name)
+                || self.deferred.contains_key(name)
+                || self.words.contains_key(&op))
+            {
+                return Err(ForthErr::Msg(format!("Not implemented {}", name)));
+            }
+        }
+        let idx = match self.xts.iter().position(|o| *o == op) {
+            Some(idx) => idx,
+            None => {
+                self.xts.push(op);
+                self.xts.len() - 1
+            }
+        };
+        Ok(idx as i64 + 1)
+    }
+    pub(crate) fn xt_op(&self, xt: i64) -> ForthResult<ForthOp> {
+        if xt < 1 || xt as usize > self.xts.len() {
+            return Err(ForthErr::Msg(format!("Invalid execution token {}", xt)));
+        }
+        Ok(self.xts[xt as usize - 1].clone())
+    }
+    fn deferred_name(&self, xt: i64) -> ForthResult<String> {
+        match self.xt_op(xt)? {
+            ForthOp::UserWord(ref name) if self.deferred.contains_key(name) => Ok(name.clone()),
+            op => Err(ForthErr::Msg(format!("{} is not a deferred word", op))),
+        }
+    }
+    // A definition replaces any earlier word, variable or value of its name.
+    fn forget(&mut self, name: &str) {
+        self.variables.remove(name);
+        self.values.remove(name);
+        self.deferred.remove(name);
+        self.words.remove(&ForthOp::UserWord(name.to_string()));
+    }
+    // TO: stores the top one or two cells of the stack in a value.
+    fn store_value(&mut self, name: &str) -> ForthResult<()> {
+        let (addr, cells) = *self
+            .values
+            .get(name)
+            .ok_or(ForthErr::Msg(format!("{} is not a value", name)))?;
+        if self.stack.len() < cells {
+            return Err(ForthErr::Msg("Empty stack".to_string()));
+        }
+        let nums = self.stack.split_off(self.stack.len() - cells);
+        match nums[..] {
+            [num] => self.store(addr, num),
+            [x1, x2] => self.store2(addr, x1, x2),
+            _ => Err(ForthErr::Msg(format!("{} is not a value", name))),
+        }
+    }
+    // The word a deferred word runs, following deferred words that are set
+    // to other deferred words. A chain longer than the number of deferred
+    // words has to go round in a circle.
+    fn deferred_target(&self, name: &str) -> ForthResult<ForthOp> {
+        let mut target = ForthOp::UserWord(name.to_string());
+        for _ in 0..=self.deferred.len() {
+            let xt = match &target {
+                ForthOp::UserWord(word) => match self.deferred.get(word) {
+                    Some(0) => {
+                        return Err(ForthErr::Msg(format!("Deferred word {} is not set", word)))
+                    }
+                    Some(xt) => *xt,
+                    None => return Ok(target),
+                },
+                _ => return Ok(target),
+            };
+            target = self.xt_op(xt)?;
+            self.check_allowed(&target)?;
         }
+        Err(ForthErr::Msg(format!(
+            "Deferred word {} calls itself",
+            name
+        )))
     }
-    pub fn push(&mut self, exp: ForthExp) {
-        self.stack.push(exp);
+    pub(crate) fn set_deferred(&mut self, name: &str, xt: i64) -> ForthResult<()> {
+        self.xt_op(xt)?;
+        let action = self
+            .deferred
+            .get_mut(name)
+            .ok_or(ForthErr::Msg(format!("{} is not a deferred word", name)))?;
+        *action = xt;
+        Ok(())
     }
-    pub fn eval_op(&self, op: ForthOp) -> ForthResult<ForthFunc> {
+    pub(crate) fn eval_op(&self, op: &ForthOp) -> ForthResult<ForthFunc> {
         let func = match op {
             ForthOp::Add => n_ary_op!(2, |x: [i64; 2]| x[1].wrapping_add(x[0])),
             ForthOp::Sub => n_ary_op!(2, |x: [i64; 2]| x[1].wrapping_sub(x[0])),
             ForthOp::Mul => n_ary_op!(2, |x: [i64; 2]| x[1].wrapping_mul(x[0])),
-            ForthOp::Div => checked_div!(2, |x: [i64; 2]| x[1] / x[0]),
-            ForthOp::Mod => checked_div!(2, |x: [i64; 2]| x[1] % x[0]),
+            ForthOp::Div => checked_div!(2, |x: [i64; 2]| x[1].wrapping_div(x[0])),
+            ForthOp::Mod => checked_div!(2, |x: [i64; 2]| x[1].wrapping_rem(x[0])),
             ForthOp::DivMod => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
@@ -94,25 +968,28 @@ impl ForthInterp {
                     restore_stack!(a, b, interp);
                     return Err(ForthErr::Msg("Division by zero".to_string()));
                 }
-                interp.push(ForthExp::Number(a % b));
-                interp.push(ForthExp::Number(a / b));
-                return Ok(());
-            }),
-            ForthOp::FMD => checked_div!(3, |x: [i64; 3]| x[2].wrapping_mul(x[1]) / x[0]),
-            ForthOp::FMDM => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
-                let c = interp.pop_num()?;
-                let b = interp.pop_num()?;
-                let a = interp.pop_num()?;
-                if c == 0 {
-                    restore_stack!(a, b, c, interp);
-                    return Err(ForthErr::Msg("Division by zero".to_string()));
-                }
-                interp.push(ForthExp::Number((a.wrapping_mul(b)) % c));
-                interp.push(ForthExp::Number((a.wrapping_mul(b)) / c));
-                return Ok(());
+                interp.push_num(a.wrapping_rem(b))?;
+                interp.push_num(a.wrapping_div(b))?;
+                Ok(())
             }),
-            ForthOp::Abs => n_ary_op!(1, |x: [i64; 1]| x[0].abs()),
-            ForthOp::Neg => n_ary_op!(1, |x: [i64; 1]| -x[0]),
+            ForthOp::MulDiv => checked_div!(3, |x: [i64; 3]| mul_div(x[2], x[1], x[0]).1),
+            ForthOp::MulDivMod => {
+                ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                    let c = interp.pop_num()?;
+                    let b = interp.pop_num()?;
+                    let a = interp.pop_num()?;
+                    if c == 0 {
+                        restore_stack!(a, b, c, interp);
+                        return Err(ForthErr::Msg("Division by zero".to_string()));
+                    }
+                    let (rem, quot) = mul_div(a, b, c);
+                    interp.push_num(rem)?;
+                    interp.push_num(quot)?;
+                    Ok(())
+                })
+            }
+            ForthOp::Abs => n_ary_op!(1, |x: [i64; 1]| x[0].wrapping_abs()),
+            ForthOp::Neg => n_ary_op!(1, |x: [i64; 1]| x[0].wrapping_neg()),
             ForthOp::Add1 => n_ary_op!(1, |x: [i64; 1]| x[0].wrapping_add(1)),
             ForthOp::Sub1 => n_ary_op!(1, |x: [i64; 1]| x[0].wrapping_sub(1)),
             ForthOp::Add2 => n_ary_op!(1, |x: [i64; 1]| x[0].wrapping_add(2)),
@@ -121,103 +998,103 @@ impl ForthInterp {
             ForthOp::Div2 => n_ary_op!(1, |x: [i64; 1]| x[0] / 2),
             ForthOp::Dup => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(a));
-                return Ok(());
+                interp.push_num(a)?;
+                interp.push_num(a)?;
+                Ok(())
             }),
             ForthOp::IfDup => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(a));
+                interp.push_num(a)?;
                 if a != 0 {
-                    interp.push(ForthExp::Number(a));
+                    interp.push_num(a)?;
                 }
-                return Ok(());
+                Ok(())
             }),
             ForthOp::Drop => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 interp.pop_num()?;
-                return Ok(());
+                Ok(())
             }),
             ForthOp::Over => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(b));
-                interp.push(ForthExp::Number(a));
-                return Ok(());
+                interp.push_num(a)?;
+                interp.push_num(b)?;
+                interp.push_num(a)?;
+                Ok(())
             }),
             ForthOp::Rot => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let c = interp.pop_num()?;
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(b));
-                interp.push(ForthExp::Number(c));
-                interp.push(ForthExp::Number(a));
-                return Ok(());
+                interp.push_num(b)?;
+                interp.push_num(c)?;
+                interp.push_num(a)?;
+                Ok(())
             }),
             ForthOp::Swap => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(b));
-                interp.push(ForthExp::Number(a));
-                return Ok(());
+                interp.push_num(b)?;
+                interp.push_num(a)?;
+                Ok(())
             }),
             ForthOp::Dup2 => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(b));
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(b));
-                return Ok(());
+                interp.push_num(a)?;
+                interp.push_num(b)?;
+                interp.push_num(a)?;
+                interp.push_num(b)?;
+                Ok(())
             }),
             ForthOp::Drop2 => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 interp.pop_num()?;
                 interp.pop_num()?;
-                return Ok(());
+                Ok(())
             }),
             ForthOp::Over2 => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let d = interp.pop_num()?;
                 let c = interp.pop_num()?;
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(b));
-                interp.push(ForthExp::Number(c));
-                interp.push(ForthExp::Number(d));
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(b));
-                return Ok(());
+                interp.push_num(a)?;
+                interp.push_num(b)?;
+                interp.push_num(c)?;
+                interp.push_num(d)?;
+                interp.push_num(a)?;
+                interp.push_num(b)?;
+                Ok(())
             }),
             ForthOp::Swap2 => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let d = interp.pop_num()?;
                 let c = interp.pop_num()?;
                 let b = interp.pop_num()?;
                 let a = interp.pop_num()?;
-                interp.push(ForthExp::Number(c));
-                interp.push(ForthExp::Number(d));
-                interp.push(ForthExp::Number(a));
-                interp.push(ForthExp::Number(b));
-                return Ok(());
+                interp.push_num(c)?;
+                interp.push_num(d)?;
+                interp.push_num(a)?;
+                interp.push_num(b)?;
+                Ok(())
             }),
             ForthOp::Pick => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
-                let n = interp.pop_num()?;
-                if n < interp.stack.len() as i64 {
-                    let t: usize = interp.stack.len() - (n + 1) as usize;
-                    interp.push(interp.stack[t].clone());
+                let n = interp.pop_usize()?;
+                if n < interp.stack.len() {
+                    let t: usize = interp.stack.len() - (n + 1);
+                    interp.push_num(interp.stack[t])?;
                 } else {
-                    interp.push(ForthExp::Number(n));
+                    interp.push_num(n as i64)?;
                     return Err(ForthErr::Msg("Not enough values".to_string()));
                 }
                 Ok(())
             }),
             ForthOp::Roll => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
-                let n = interp.pop_num()?;
-                if n < interp.stack.len() as i64 {
-                    let t: usize = interp.stack.len() - (n + 1) as usize;
+                let n = interp.pop_usize()?;
+                if n < interp.stack.len() {
+                    let t: usize = interp.stack.len() - (n + 1);
                     let val = interp.stack.remove(t);
-                    interp.push(val);
+                    interp.push_num(val)?;
                 } else {
-                    interp.push(ForthExp::Number(n));
+                    interp.push_num(n as i64)?;
                     return Err(ForthErr::Msg("Not enough values".to_string()));
                 }
                 Ok(())
@@ -225,17 +1102,87 @@ impl ForthInterp {
             ForthOp::Print => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
                 let a = interp.pop_num()?;
                 println!("{} ", a);
-                return Ok(());
+                Ok(())
+            }),
+            ForthOp::PrintStack => {
+                ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                    println!("{}", interp.format_stack());
+                    Ok(())
+                })
+            }
+            ForthOp::PrintAt => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                match interp.fetch(addr) {
+                    Ok(num) => println!("{} ", num),
+                    Err(e) => {
+                        restore_stack!(addr, interp);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::Dump => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let len = interp.pop_usize()?;
+                let addr = interp.pop_num()?;
+                match interp.hex_dump(addr, len) {
+                    Ok(dump) => print!("{}", dump),
+                    Err(e) => {
+                        restore_stack!(addr, len as i64, interp);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::Emit => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let c = interp.pop_num()?;
+                print!("{}", std::char::from_u32(c as u32).unwrap_or('?'));
+                Ok(())
+            }),
+            ForthOp::Type => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let s = interp.pop_str()?;
+                print!("{}", s);
+                Ok(())
+            }),
+            ForthOp::Key => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let mut buf = [0u8; 1];
+                match io::stdin().read(&mut buf) {
+                    Ok(1) => interp.push_num(buf[0] as i64)?,
+                    _ => return Err(ForthErr::Msg("End of input".to_string())),
+                }
+                Ok(())
+            }),
+            ForthOp::Accept => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let max = interp.pop_usize()?;
+                let addr = interp.pop_num()?;
+                let mut line = String::new();
+                io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .map_err(|e| ForthErr::Msg(e.to_string()))?;
+                let line = line.trim_end_matches(&['\r', '\n'][..]);
+                let bytes = &line.as_bytes()[..line.len().min(max)];
+                for (i, b) in bytes.iter().enumerate() {
+                    interp.store_char(addr + i as i64, *b as i64)?;
+                }
+                interp.push_num(bytes.len() as i64)?;
+                Ok(())
             }),
             ForthOp::Depth => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
-                interp.push(ForthExp::Number(interp.stack.len() as i64));
-                return Ok(());
+                interp.push_num(interp.stack.len() as i64)?;
+                Ok(())
             }),
             ForthOp::And => n_ary_op!(2, |x: [i64; 2]| x[0] & x[1]),
             ForthOp::Or => n_ary_op!(2, |x: [i64; 2]| x[0] | x[1]),
             ForthOp::Xor => n_ary_op!(2, |x: [i64; 2]| x[0] ^ x[1]),
-            ForthOp::Not => n_ary_op!(1, |x: [i64; 1]| !x[0]),
-            ForthOp::Invert => n_ary_op!(1, |x: [i64; 1]| if x[0] != FALSE { FALSE } else { TRUE }),
+            ForthOp::Not | ForthOp::Invert => {
+                // NOT is a synonym for 0=, as in most Forths that still define
+                // it. Legacy mode swaps the two words back.
+                if (*op == ForthOp::Not) != self.legacy_invert {
+                    n_ary_op!(1, |x: [i64; 1]| if x[0] != FALSE { FALSE } else { TRUE })
+                } else {
+                    n_ary_op!(1, |x: [i64; 1]| !x[0])
+                }
+            }
             ForthOp::Lt => n_ary_op!(2, |x: [i64; 2]| if x[1] < x[0] { TRUE } else { FALSE }),
             ForthOp::Gt => n_ary_op!(2, |x: [i64; 2]| if x[1] > x[0] { TRUE } else { FALSE }),
             ForthOp::Eq => n_ary_op!(2, |x: [i64; 2]| if x[1] == x[0] { TRUE } else { FALSE }),
@@ -248,130 +1195,2124 @@ impl ForthInterp {
             ForthOp::Ne0 => n_ary_op!(1, |x: [i64; 1]| if x[0] != 0 { TRUE } else { FALSE }),
             ForthOp::True => n_ary_op!(0, |_: [i64; 0]| TRUE),
             ForthOp::False => n_ary_op!(0, |_: [i64; 0]| FALSE),
-            ForthOp::Lshift => n_ary_op!(2, |x: [i64; 2]| x[1] << x[0]),
-            ForthOp::Rshift => n_ary_op!(2, |x: [i64; 2]| x[1] >> x[0]),
-            ForthOp::Max => n_ary_op!(2, |x: [i64; 2]| max(x[0], x[1])),
-            ForthOp::Min => n_ary_op!(2, |x: [i64; 2]| min(x[0], x[1])),
-            ForthOp::Variable(_) => ForthFunc::Variable,
-            ForthOp::GetVar(num) => self
-                .words
-                .get(&ForthOp::GetVar(num))
-                .ok_or(ForthErr::Msg(format!("Not defined variable at {}", num)))?
-                .clone(),
-            ForthOp::SetVar(num) => self
-                .words
-                .get(&ForthOp::SetVar(num))
-                .ok_or(ForthErr::Msg(format!("Not defined variable at {}", num)))?
-                .clone(),
+            ForthOp::Lshift => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let n = interp.pop_num()?;
+                let a = interp.pop_num()?;
+                interp.push_num(interp.shift_count(n).map_or(0, |n| a << n))?;
+                Ok(())
+            }),
+            ForthOp::Rshift => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let n = interp.pop_num()?;
+                let a = interp.pop_num()?;
+                let a = interp.cell_width.unsigned(a);
+                interp.push_num(interp.shift_count(n).map_or(0, |n| (a >> n) as i64))?;
+                Ok(())
+            }),
+            ForthOp::Max => n_ary_op!(2, |x: [i64; 2]| max(x[0], x[1])),
+            ForthOp::Min => n_ary_op!(2, |x: [i64; 2]| min(x[0], x[1])),
+            ForthOp::Fetch => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                match interp.fetch(addr) {
+                    Ok(num) => interp.push_num(num)?,
+                    Err(e) => {
+                        restore_stack!(addr, interp);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::TwoFetch => mem_op!(1, |interp: &mut ForthInterp, x: [i64; 1]| {
+                let (x1, x2) = interp.fetch2(x[0])?;
+                interp.push_nums(&[x1, x2])?;
+                Ok(())
+            }),
+            ForthOp::TwoStore => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| interp
+                .store2(x[0], x[2], x[1])),
+            ForthOp::Store => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                let num = interp.pop_num()?;
+                if let Err(e) = interp.store(addr, num) {
+                    restore_stack!(num, addr, interp);
+                    return Err(e);
+                }
+                Ok(())
+            }),
+            ForthOp::AddStore => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                let num = interp.pop_num()?;
+                let res = interp.fetch(addr).and_then(|old| {
+                    let new = interp.cell_width.wrap(old.wrapping_add(num));
+                    interp.store(addr, new)
+                });
+                if let Err(e) = res {
+                    restore_stack!(num, addr, interp);
+                    return Err(e);
+                }
+                Ok(())
+            }),
+            ForthOp::CFetch => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                match interp.fetch_char(addr) {
+                    Ok(num) => interp.push_num(num)?,
+                    Err(e) => {
+                        restore_stack!(addr, interp);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::CStore => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                let num = interp.pop_num()?;
+                if let Err(e) = interp.store_char(addr, num) {
+                    restore_stack!(num, addr, interp);
+                    return Err(e);
+                }
+                Ok(())
+            }),
+            ForthOp::Compare => mem_op!(4, |interp: &mut ForthInterp, x: [i64; 4]| {
+                let s1 = interp.mem(x[3], x[2])?;
+                let s2 = interp.mem(x[1], x[0])?;
+                let res = match s1.cmp(s2) {
+                    Ordering::Less => -1,
+                    Ordering::Equal => 0,
+                    Ordering::Greater => 1,
+                };
+                interp.push_num(res)?;
+                Ok(())
+            }),
+            ForthOp::Search => mem_op!(4, |interp: &mut ForthInterp, x: [i64; 4]| {
+                let hay = interp.mem(x[3], x[2])?;
+                let needle = interp.mem(x[1], x[0])?;
+                let found = if needle.is_empty() {
+                    Some(0)
+                } else {
+                    hay.windows(needle.len()).position(|w| w == needle)
+                };
+                match found {
+                    Some(i) => {
+                        interp.push_num(x[3] + i as i64)?;
+                        interp.push_num(x[2] - i as i64)?;
+                        interp.push_num(TRUE)?;
+                    }
+                    None => {
+                        interp.push_num(x[3])?;
+                        interp.push_num(x[2])?;
+                        interp.push_num(FALSE)?;
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::SlashString => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                interp.push_num(x[2].wrapping_add(x[0]))?;
+                interp.push_num(x[1].wrapping_sub(x[0]))?;
+                ForthResult::Ok(())
+            }),
+            ForthOp::DashTrailing => mem_op!(2, |interp: &mut ForthInterp, x: [i64; 2]| {
+                let spaces = interp
+                    .mem(x[1], x[0])?
+                    .iter()
+                    .rev()
+                    .take_while(|&&b| b == b' ')
+                    .count();
+                interp.push_num(x[1])?;
+                interp.push_num(x[0] - spaces as i64)?;
+                Ok(())
+            }),
+            ForthOp::Blank => mem_op!(2, |interp: &mut ForthInterp, x: [i64; 2]| interp
+                .fill(x[1], x[0], b' ')),
+            ForthOp::Erase => mem_op!(2, |interp: &mut ForthInterp, x: [i64; 2]| interp
+                .fill(x[1], x[0], 0)),
+            ForthOp::Fill => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| interp
+                .fill(x[2], x[1], x[0] as u8)),
+            ForthOp::Move => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                let src = interp.mem(x[2], x[0])?.to_vec();
+                interp.mem_mut(x[1], x[0])?.copy_from_slice(&src);
+                Ok(())
+            }),
+            // CMOVE and CMOVE> copy a byte at a time, so overlapping moves
+            // repeat the bytes they have already copied.
+            ForthOp::CMove => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                interp.mem(x[2], x[0])?;
+                interp.mem(x[1], x[0])?;
+                for i in 0..x[0] {
+                    let byte = interp.fetch_char(x[2] + i)?;
+                    interp.store_char(x[1] + i, byte)?;
+                }
+                Ok(())
+            }),
+            ForthOp::CMoveUp => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                interp.mem(x[2], x[0])?;
+                interp.mem(x[1], x[0])?;
+                for i in (0..x[0]).rev() {
+                    let byte = interp.fetch_char(x[2] + i)?;
+                    interp.store_char(x[1] + i, byte)?;
+                }
+                Ok(())
+            }),
+            ForthOp::SPlus => mem_op!(4, |interp: &mut ForthInterp, x: [i64; 4]| {
+                let (addr, len) = interp.join(x[3], x[2], x[1], x[0])?;
+                interp.push_num(addr)?;
+                interp.push_num(len)?;
+                Ok(())
+            }),
+            ForthOp::ReadOnly => n_ary_op!(0, |_: [i64; 0]| file::FAM_READ),
+            ForthOp::WriteOnly => n_ary_op!(0, |_: [i64; 0]| file::FAM_WRITE),
+            ForthOp::ReadWrite => n_ary_op!(0, |_: [i64; 0]| file::FAM_READ | file::FAM_WRITE),
+            ForthOp::Bin => n_ary_op!(1, |x: [i64; 1]| x[0] | file::FAM_BIN),
+            ForthOp::CreateFile => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| interp
+                .open_file(x, true)),
+            ForthOp::OpenFile => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| interp
+                .open_file(x, false)),
+            ForthOp::CloseFile => mem_op!(1, |interp: &mut ForthInterp, x: [i64; 1]| {
+                let ior = interp.files.close(x[0]);
+                interp.push_num(ior)?;
+                ForthResult::Ok(())
+            }),
+            ForthOp::ReadFile => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                let files = interp.files.clone();
+                match files.read(x[0], interp.mem_mut(x[2], x[1])?) {
+                    Ok(len) => interp.push_nums(&[len as i64, 0])?,
+                    Err(ior) => interp.push_nums(&[0, ior])?,
+                }
+                Ok(())
+            }),
+            ForthOp::ReadLine => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                let files = interp.files.clone();
+                match files.read_line(x[0], interp.mem_mut(x[2], x[1])?) {
+                    Ok((len, more)) => {
+                        interp.push_nums(&[len as i64, if more { TRUE } else { FALSE }, 0])?
+                    }
+                    Err(ior) => interp.push_nums(&[0, FALSE, ior])?,
+                }
+                Ok(())
+            }),
+            ForthOp::WriteFile => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                let ior = interp.files.write(x[0], interp.mem(x[2], x[1])?);
+                interp.push_num(ior)?;
+                Ok(())
+            }),
+            ForthOp::WriteLine => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                let mut line = interp.mem(x[2], x[1])?.to_vec();
+                line.push(b'\n');
+                let ior = interp.files.write(x[0], &line);
+                interp.push_num(ior)?;
+                Ok(())
+            }),
+            ForthOp::FileSize => mem_op!(1, |interp: &mut ForthInterp, x: [i64; 1]| {
+                let size = interp.files.size(x[0]);
+                interp.push_offset(size)
+            }),
+            ForthOp::FilePosition => mem_op!(1, |interp: &mut ForthInterp, x: [i64; 1]| {
+                let pos = interp.files.position(x[0]);
+                interp.push_offset(pos)
+            }),
+            // The position is a double cell; files beyond one cell aren't supported.
+            ForthOp::RepositionFile => mem_op!(3, |interp: &mut ForthInterp, x: [i64; 3]| {
+                let ior = if x[1] != 0 || x[2] < 0 {
+                    file::IOR_IO
+                } else {
+                    interp.files.reposition(x[0], x[2] as u64)
+                };
+                interp.push_num(ior)?;
+                ForthResult::Ok(())
+            }),
+            ForthOp::DeleteFile => mem_op!(2, |interp: &mut ForthInterp, x: [i64; 2]| {
+                let path = interp.mem_path(x[1], x[0])?;
+                interp.push_num(file::delete(&path))?;
+                Ok(())
+            }),
+            ForthOp::EnvironmentQuery => mem_op!(2, |interp: &mut ForthInterp, x: [i64; 2]| {
+                let query = interp.mem_path(x[1], x[0])?;
+                match interp.environment(&query) {
+                    Some(value) => interp.push_nums(&[value, -1])?,
+                    None => interp.push_num(0)?,
+                }
+                ForthResult::Ok(())
+            }),
+            ForthOp::RenameFile => mem_op!(4, |interp: &mut ForthInterp, x: [i64; 4]| {
+                let from = interp.mem_path(x[3], x[2])?;
+                let to = interp.mem_path(x[1], x[0])?;
+                interp.push_num(file::rename(&from, &to))?;
+                Ok(())
+            }),
+            ForthOp::Allocate => mem_op!(1, |interp: &mut ForthInterp, x: [i64; 1]| {
+                match interp.allocate(x[0]) {
+                    Some(addr) => interp.push_nums(&[addr, 0])?,
+                    None => interp.push_nums(&[0, heap::IOR_ALLOCATE])?,
+                }
+                ForthResult::Ok(())
+            }),
+            ForthOp::Free => mem_op!(1, |interp: &mut ForthInterp, x: [i64; 1]| {
+                let ior = if interp.heap.free(x[0]) {
+                    0
+                } else {
+                    heap::IOR_FREE
+                };
+                interp.push_num(ior)?;
+                ForthResult::Ok(())
+            }),
+            ForthOp::Resize => mem_op!(2, |interp: &mut ForthInterp, x: [i64; 2]| {
+                match interp.resize(x[1], x[0]) {
+                    Some(addr) => interp.push_nums(&[addr, 0])?,
+                    None => interp.push_nums(&[x[1], heap::IOR_RESIZE])?,
+                }
+                ForthResult::Ok(())
+            }),
+            ForthOp::Here => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                interp.push_num(interp.memory.len() as i64)?;
+                Ok(())
+            }),
+            ForthOp::Allot => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let n = interp.pop_num()?;
+                if let Err(e) = interp.allot(n) {
+                    restore_stack!(n, interp);
+                    return Err(e);
+                }
+                Ok(())
+            }),
+            ForthOp::Comma => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let num = interp.pop_num()?;
+                match interp.allot(interp.cell_width.bytes() as i64) {
+                    Ok(addr) => interp.store(addr, num)?,
+                    Err(e) => {
+                        restore_stack!(num, interp);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::CComma => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let num = interp.pop_num()?;
+                match interp.allot(1) {
+                    Ok(addr) => interp.store_char(addr, num)?,
+                    Err(e) => {
+                        restore_stack!(num, interp);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }),
+            ForthOp::Cells => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let n = interp.pop_num()?;
+                interp.push_num(n.wrapping_mul(interp.cell_width.bytes() as i64))?;
+                Ok(())
+            }),
+            ForthOp::CellAdd => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let addr = interp.pop_num()?;
+                interp.push_num(addr.wrapping_add(interp.cell_width.bytes() as i64))?;
+                Ok(())
+            }),
+            ForthOp::Chars => n_ary_op!(1, |x: [i64; 1]| x[0]),
+            ForthOp::CharAdd => n_ary_op!(1, |x: [i64; 1]| x[0].wrapping_add(1)),
+            ForthOp::Execute => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let xt = interp.pop_num()?;
+                match interp.xt_op(xt) {
+                    Ok(op) => interp.eval(&ForthExp::Op(op)),
+                    Err(e) => {
+                        restore_stack!(xt, interp);
+                        Err(e)
+                    }
+                }
+            }),
+            ForthOp::DeferFetch => {
+                ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                    let xt = interp.pop_num()?;
+                    match interp.deferred_name(xt) {
+                        Ok(name) => interp.push_num(interp.deferred[&name])?,
+                        Err(e) => {
+                            restore_stack!(xt, interp);
+                            return Err(e);
+                        }
+                    }
+                    Ok(())
+                })
+            }
+            ForthOp::DeferStore => {
+                ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                    let xt = interp.pop_num()?;
+                    let action = interp.pop_num()?;
+                    let res = interp
+                        .deferred_name(xt)
+                        .and_then(|name| interp.set_deferred(&name, action));
+                    if let Err(e) = res {
+                        restore_stack!(action, xt, interp);
+                        return Err(e);
+                    }
+                    Ok(())
+                })
+            }
+            ForthOp::TestArrow => {
+                ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                    let len = interp.stack.len();
+                    let mark = interp
+                        .test_marks
+                        .last_mut()
+                        .ok_or(ForthErr::Msg("-> without T{".to_string()))?;
+                    mark.depth = mark.depth.min(len);
+                    mark.actual = Some(interp.stack.split_off(mark.depth));
+                    Ok(())
+                })
+            }
+            ForthOp::TestEnd => ForthFunc::Native(|interp: &mut ForthInterp| -> ForthResult<()> {
+                let mark = match interp.test_marks.pop() {
+                    Some(TestMark {
+                        line,
+                        depth,
+                        actual: Some(actual),
+                    }) => (line, depth, actual),
+                    Some(_) => return Err(ForthErr::Msg("}T without ->".to_string())),
+                    None => return Err(ForthErr::Msg("}T without T{".to_string())),
+                };
+                let (line, depth, actual) = mark;
+                let expected = interp.stack.split_off(depth.min(interp.stack.len()));
+                if expected == actual {
+                    interp.test_results.passed += 1;
+                } else {
+                    interp.test_results.failures.push(TestFailure {
+                        line,
+                        expected,
+                        actual,
+                    });
+                }
+                Ok(())
+            }),
+            ForthOp::TestStart(line) => ForthFunc::TestStart(*line),
+            ForthOp::Locals(args, total) => ForthFunc::Locals(*args, *total),
+            ForthOp::Local(n) => ForthFunc::Local(*n),
+            ForthOp::LocalStore(n) => ForthFunc::LocalStore(*n),
+            ForthOp::Variable(name) => ForthFunc::Variable(name.clone(), 1),
+            ForthOp::TwoVariable(name) => ForthFunc::Variable(name.clone(), 2),
+            ForthOp::Constant(name) => ForthFunc::ConstantDef(name.clone(), 1),
+            ForthOp::TwoConstant(name) => ForthFunc::ConstantDef(name.clone(), 2),
+            ForthOp::Value(name) => ForthFunc::ValueDef(name.clone(), 1),
+            ForthOp::TwoValue(name) => ForthFunc::ValueDef(name.clone(), 2),
+            ForthOp::To(name) => ForthFunc::To(name.clone()),
+            ForthOp::PlusTo(name) => ForthFunc::PlusTo(name.clone()),
+            ForthOp::Tick(op) => ForthFunc::Tick((**op).clone()),
+            ForthOp::Defer(name) => ForthFunc::Defer(name.clone()),
+            ForthOp::Is(name) => ForthFunc::Is(name.clone()),
+            ForthOp::ActionOf(name) => ForthFunc::ActionOf(name.clone()),
+            ForthOp::SaveSystem(path) => ForthFunc::SaveSystem(path.clone()),
+            ForthOp::SQuote(text) => {
+                return Err(ForthErr::Msg(format!("S\" {}\" was not compiled", text)))
+            }
             ForthOp::UserWord(name) => {
-                if self.variables.contains_key(&name) {
-                    ForthFunc::Variable
+                if let Some(addr) = self.variables.get(name) {
+                    ForthFunc::Constant(*addr)
+                } else if let Some((addr, cells)) = self.values.get(name) {
+                    ForthFunc::Value(*addr, *cells)
+                } else if self.deferred.contains_key(name) {
+                    self.eval_op(&self.deferred_target(name)?)?
                 } else {
                     self.words
-                        .get(&ForthOp::UserWord(name.clone()))
+                        .get(op)
                         .ok_or(ForthErr::Msg(format!("Not implemented {}", name)))?
                         .clone()
                 }
             }
             ForthOp::IfThenElse(num) => self
                 .words
-                .get(&ForthOp::IfThenElse(num))
+                .get(op)
                 .ok_or(ForthErr::Msg(format!("No body for if at {}", num)))?
                 .clone(),
-            ForthOp::BeginUntil(num) => self
-                .words
-                .get(&ForthOp::BeginUntil(num))
-                .ok_or(ForthErr::Msg(format!("No body for cycle at {}", num)))?
-                .clone(),
-            ForthOp::BeginWhile(num) => self
+            ForthOp::BeginUntil(num) | ForthOp::BeginWhile(num) => self
                 .words
-                .get(&ForthOp::BeginWhile(num))
+                .get(op)
                 .ok_or(ForthErr::Msg(format!("No body for cycle at {}", num)))?
                 .clone(),
         };
 
         Ok(func)
     }
-    pub fn eval(&mut self, exp: ForthExp) -> ForthResult<()> {
-        match exp {
+    // Calls and control structures push frames rather than recursing, so
+    // deep recursion in Forth doesn't use up the native stack.
+    pub(crate) fn eval(&mut self, exp: &ForthExp) -> ForthResult<()> {
+        let mut frames = vec![];
+        let mut next = self.begin(exp);
+        let mut res = loop {
+            match next {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+            let frame = match frames.last_mut() {
+                Some(frame) => frame,
+                None => break Ok(()),
+            };
+            next = match frame.body.get(frame.pc) {
+                Some(exp) => {
+                    frame.pc += 1;
+                    self.begin(exp)
+                }
+                None => self.end(&mut frames).map(|_| None),
+            };
+        };
+        while let Some(frame) = frames.pop() {
+            res = self.leave(frame, res);
+        }
+        // A full stack is cleared, as ABORT would, so the next line can run.
+        if res == Err(ForthErr::LimitExceeded(Limit::StackDepth)) {
+            self.stack.clear();
+        }
+        res
+    }
+    // Starts one step: runs it, or returns the frame it continues in.
+    fn begin(&mut self, exp: &ForthExp) -> ForthResult<Option<Frame>> {
+        self.count_step()?;
+        let step = match &self.tracer {
+            Some(tracer) => {
+                let step = self.trace_step(exp);
+                tracer.clone().borrow_mut().before(self, &step)?;
+                Some(step)
+            }
+            None => None,
+        };
+        match self.exec(exp) {
+            Ok(Some((body, kind))) => Ok(Some(Frame {
+                body,
+                pc: 0,
+                kind,
+                step,
+            })),
+            Ok(None) => self.finish(step, Ok(())).map(|_| None),
+            Err(e) => self.finish(step, Err(e)).map(|_| None),
+        }
+    }
+    // The top frame ran to its end: loops go round again, the rest return.
+    fn end(&mut self, frames: &mut Vec<Frame>) -> ForthResult<()> {
+        if let Some(frame) = frames.last_mut() {
+            let again = match &mut frame.kind {
+                FrameKind::Until => self.pop_num()? == 0,
+                FrameKind::While(other, in_head) => {
+                    if *in_head && self.pop_num()? == 0 {
+                        false
+                    } else {
+                        std::mem::swap(&mut frame.body, other);
+                        *in_head = !*in_head;
+                        true
+                    }
+                }
+                _ => false,
+            };
+            if again {
+                frame.pc = 0;
+                return Ok(());
+            }
+        }
+        match frames.pop() {
+            Some(frame) => self.leave(frame, Ok(())),
+            None => Ok(()),
+        }
+    }
+    fn leave(&mut self, frame: Frame, res: ForthResult<()>) -> ForthResult<()> {
+        if let FrameKind::Call(caller) = frame.kind {
+            self.locals.truncate(self.frame);
+            self.frame = caller;
+            self.call_depth -= 1;
+        }
+        self.finish(frame.step, res)
+    }
+    fn finish(&mut self, step: Option<TraceStep>, res: ForthResult<()>) -> ForthResult<()> {
+        match (step, &self.tracer) {
+            (Some(step), Some(tracer)) => {
+                let after = tracer.clone().borrow_mut().after(self, &step);
+                after.and(res)
+            }
+            _ => res,
+        }
+    }
+    fn trace_step(&self, exp: &ForthExp) -> TraceStep {
+        let (word, call) = match exp {
+            ForthExp::Number(n) => (n.to_string(), false),
             ForthExp::Op(op) => {
-                let func = self.eval_op(op)?.clone();
-                match func {
-                    ForthFunc::Native(f) => f(self)?,
-                    ForthFunc::User(v) => {
-                        for e in v {
-                            self.eval(e)?;
-                        }
+                let call = match op {
+                    ForthOp::UserWord(name) => {
+                        self.deferred.contains_key(name)
+                            || matches!(self.words.get(op), Some(ForthFunc::User(_)))
                     }
-                    ForthFunc::Variable => {}
-                    ForthFunc::ConstantDef(name) => match self.pop_num() {
-                        Ok(num) => {
-                            self.words.insert(
-                                ForthOp::UserWord(name.clone()),
-                                ForthFunc::User(vec![ForthExp::Number(num)]),
-                            );
-                        }
-                        Err(_) => return Err(ForthErr::Msg("No constant value".to_string())),
-                    },
-                    ForthFunc::IfThenElse((then, r#else)) => {
-                        let cond = self.pop_num()?;
-                        if cond != 0 {
-                            for e in then {
-                                self.eval(e)?;
-                            }
-                        } else if let Some(v) = r#else {
-                            for e in v {
-                                self.eval(e)?;
-                            }
-                        }
+                    _ => false,
+                };
+                (word_name(op), call)
+            }
+        };
+        TraceStep {
+            word,
+            depth: self.call_depth,
+            call,
+        }
+    }
+    // Runs a step, or returns the body it continues in for `eval` to run.
+    fn exec(&mut self, exp: &ForthExp) -> ForthResult<Option<(Body, FrameKind)>> {
+        let mut op = match exp {
+            ForthExp::Number(a) => {
+                self.push_num(*a)?;
+                return Ok(None);
+            }
+            ForthExp::Op(op) => op,
+        };
+        // EXECUTE runs its word as part of this step, so recursion through
+        // it doesn't nest native calls either.
+        let target;
+        if let ForthOp::Execute = op {
+            let xt = self.pop_num()?;
+            target = match self.xt_op(xt) {
+                Ok(target) => target,
+                Err(e) => {
+                    restore_stack!(xt, self);
+                    return Err(e);
+                }
+            };
+            op = &target;
+        }
+        // Compiled code was checked already, but EXECUTE, deferred words and
+        // definitions from before a restriction can still reach gated words.
+        if self.capabilities != Capabilities::all() {
+            self.check_allowed(op)?;
+        }
+        let nested = match self.eval_op(op)? {
+            ForthFunc::User(body) => (body, self.call()?),
+            ForthFunc::IfThenElse((then, r#else)) => match (self.pop_bool()?, r#else) {
+                (true, _) => (then, FrameKind::Branch),
+                (false, Some(r#else)) => (r#else, FrameKind::Branch),
+                (false, None) => return Ok(None),
+            },
+            ForthFunc::BeginUntil(body) => (body, FrameKind::Until),
+            ForthFunc::BeginWhile(head, body) => (head, FrameKind::While(body, true)),
+            func => {
+                self.exec_func(func)?;
+                return Ok(None);
+            }
+        };
+        Ok(Some(nested))
+    }
+    fn exec_func(&mut self, func: ForthFunc) -> ForthResult<()> {
+        match func {
+            ForthFunc::Native(f) => f(self)?,
+            ForthFunc::Host(f) => f(self)?,
+            ForthFunc::Constant(num) => self.push_num(num)?,
+            ForthFunc::TwoConstant(n1, n2) => self.push_nums(&[n1, n2])?,
+            ForthFunc::Variable(name, cells) => {
+                self.check_words(1)?;
+                let addr = self.allot((cells * self.cell_width.bytes()) as i64)?;
+                self.forget(&name);
+                self.variables.insert(name, addr);
+            }
+            ForthFunc::ConstantDef(name, cells) => {
+                if self.stack.len() < cells {
+                    return Err(ForthErr::Msg("No constant value".to_string()));
+                }
+                self.check_words(1)?;
+                let nums = self.stack.split_off(self.stack.len() - cells);
+                let func = match nums[..] {
+                    [n1, n2] => ForthFunc::TwoConstant(n1, n2),
+                    _ => ForthFunc::Constant(nums[0]),
+                };
+                self.forget(&name);
+                self.words.insert(ForthOp::UserWord(name), func);
+            }
+            ForthFunc::ValueDef(name, cells) => {
+                if self.stack.len() < cells {
+                    return Err(ForthErr::Msg("No initial value".to_string()));
+                }
+                self.check_words(1)?;
+                let addr = self.allot((cells * self.cell_width.bytes()) as i64)?;
+                self.forget(&name);
+                self.values.insert(name.clone(), (addr, cells));
+                self.store_value(&name)?;
+            }
+            ForthFunc::Value(addr, 1) => {
+                let num = self.fetch(addr)?;
+                self.push_num(num)?;
+            }
+            ForthFunc::Value(addr, _) => {
+                let (x1, x2) = self.fetch2(addr)?;
+                self.push_nums(&[x1, x2])?;
+            }
+            ForthFunc::To(name) => self.store_value(&name)?,
+            ForthFunc::PlusTo(name) => match self.values.get(&name) {
+                Some(&(addr, 1)) => {
+                    let num = self.pop_num()?;
+                    self.store(addr, self.fetch(addr)?.wrapping_add(num))?;
+                }
+                _ => {
+                    return Err(ForthErr::Msg(format!(
+                        "{} is not a single cell value",
+                        name
+                    )))
+                }
+            },
+            ForthFunc::Tick(op) => {
+                let xt = self.xt_of(op)?;
+                self.push_num(xt)?;
+            }
+            ForthFunc::Defer(name) => {
+                self.check_words(1)?;
+                self.deferred.insert(name, 0);
+            }
+            ForthFunc::Is(name) => {
+                let xt = self.pop_num()?;
+                self.set_deferred(&name, xt)?;
+            }
+            ForthFunc::ActionOf(name) => {
+                let xt = *self
+                    .deferred
+                    .get(&name)
+                    .ok_or(ForthErr::Msg(format!("{} is not a deferred word", name)))?;
+                self.push_num(xt)?;
+            }
+            ForthFunc::SaveSystem(path) => self.save_image(path)?,
+            ForthFunc::TestStart(line) => self.test_marks.push(TestMark {
+                line,
+                depth: self.stack.len(),
+                actual: None,
+            }),
+            ForthFunc::Locals(args, total) => {
+                if self.stack.len() < args {
+                    return Err(ForthErr::Msg("Empty stack".to_string()));
+                }
+                let values = self.stack.split_off(self.stack.len() - args);
+                self.locals.truncate(self.frame);
+                self.locals.extend(values);
+                self.locals.resize(self.frame + total, 0);
+            }
+            ForthFunc::Local(n) => {
+                let num = *self.local(n)?;
+                self.push_num(num)?;
+            }
+            ForthFunc::LocalStore(n) => {
+                let num = self.pop_num()?;
+                match self.local(n) {
+                    Ok(local) => *local = num,
+                    Err(e) => {
+                        restore_stack!(num, self);
+                        return Err(e);
                     }
-                    ForthFunc::GetVar(name) => {
-                        let num = self
-                            .variables
-                            .get(&name)
-                            .ok_or(ForthErr::Msg(format!("Not defined variable at {}", name)))?
-                            .clone();
-                        self.push(ForthExp::Number(num));
+                }
+            }
+            ForthFunc::User(_)
+            | ForthFunc::IfThenElse(_)
+            | ForthFunc::BeginUntil(_)
+            | ForthFunc::BeginWhile(..) => unreachable!(),
+        }
+        Ok(())
+    }
+    // Saves the dictionary, data space, variables and data stack. Host natives
+    // are not saved: register them again before loading the image.
+    pub fn save_image<P: AsRef<Path>>(&self, path: P) -> ForthResult<()> {
+        let mut out = BufWriter::new(fs::File::create(path).map_err(image::io_err)?);
+        self.write_image(&mut out)?;
+        out.flush().map_err(image::io_err)
+    }
+    pub fn load_image<P: AsRef<Path>>(&mut self, path: P) -> ForthResult<()> {
+        let mut input = BufReader::new(fs::File::open(path).map_err(image::io_err)?);
+        self.read_image(&mut input)
+    }
+    pub fn write_image(&self, out: &mut dyn Write) -> ForthResult<()> {
+        let mut w = ImageWriter::new(out);
+        w.header()?;
+        w.len(self.cell_width.bits() as usize)?;
+        w.len(self.parser.position())?;
+        w.len(self.stack.len())?;
+        for num in &self.stack {
+            w.num(*num)?;
+        }
+        w.blob(&self.memory)?;
+        w.len(self.heap.blocks().count())?;
+        for (addr, bytes) in self.heap.blocks() {
+            w.num(addr)?;
+            w.blob(bytes)?;
+        }
+        let mut variables: Vec<_> = self.variables.iter().collect();
+        variables.sort();
+        w.len(variables.len())?;
+        for (name, addr) in variables {
+            w.str(name)?;
+            w.num(*addr)?;
+        }
+        let mut values: Vec<_> = self.values.iter().collect();
+        values.sort();
+        w.len(values.len())?;
+        for (name, (addr, cells)) in values {
+            w.str(name)?;
+            w.num(*addr)?;
+            w.len(*cells)?;
+        }
+        let mut deferred: Vec<_> = self.deferred.iter().collect();
+        deferred.sort();
+        w.len(deferred.len())?;
+        for (name, xt) in deferred {
+            w.str(name)?;
+            w.num(*xt)?;
+        }
+        w.len(self.xts.len())?;
+        for op in &self.xts {
+            w.op(op)?;
+        }
+        let mut effects: Vec<_> = self.effects.iter().collect();
+        effects.sort_by(|a, b| a.0.cmp(b.0));
+        w.len(effects.len())?;
+        for (name, effect) in effects {
+            w.str(name)?;
+            w.effect(*effect)?;
+        }
+        let words: Vec<_> = self
+            .words
+            .iter()
+            .filter(|(_, func)| !matches!(func, ForthFunc::Host(_)))
+            .collect();
+        w.len(words.len())?;
+        for (op, func) in words {
+            w.op(op)?;
+            w.func(func)?;
+        }
+        Ok(())
+    }
+    // Replaces the interpreter state with an image. Settings such as limits,
+    // capabilities and the tracer are kept, and so are registered host natives
+    // that the image doesn't redefine. Nothing changes if the image is invalid.
+    pub fn read_image(&mut self, input: &mut dyn Read) -> ForthResult<()> {
+        let mut r = ImageReader::new(input);
+        r.header()?;
+        let corrupt = |what: &str| ForthErr::Msg(format!("Corrupt image: {}", what));
+        let cell_width =
+            CellWidth::from_bits(r.len()? as u32).ok_or_else(|| corrupt("cell width"))?;
+        let cell = cell_width.bytes() as i64;
+        let position = r.len()?;
+        let mut stack = vec![];
+        for _ in 0..r.len()? {
+            let num = r.num()?;
+            if cell_width.wrap(num) != num {
+                return Err(corrupt("stack"));
+            }
+            stack.push(num);
+        }
+        let memory = r.blob()?;
+        if memory.len() > data_space_limit(cell_width) {
+            return Err(corrupt("data space"));
+        }
+        let in_memory = |addr: i64, len: i64| {
+            addr >= 0
+                && addr
+                    .checked_add(len)
+                    .is_some_and(|end| end <= memory.len() as i64)
+        };
+        // Blocks come in address order and may not overlap.
+        let space = heap_space(cell_width);
+        let mut free = space.start;
+        let mut heap = Heap::default();
+        for _ in 0..r.len()? {
+            let addr = r.num()?;
+            let bytes = r.blob()?;
+            free = match addr.checked_add((bytes.len() as i64).max(1)) {
+                Some(end) if addr >= free && end <= space.end => end,
+                _ => return Err(corrupt("heap")),
+            };
+            heap.insert(addr, bytes);
+        }
+        let mut variables = HashMap::new();
+        for _ in 0..r.len()? {
+            let (name, addr) = (r.str()?, r.num()?);
+            if !in_memory(addr, cell) {
+                return Err(corrupt("variable"));
+            }
+            variables.insert(name, addr);
+        }
+        let mut values = HashMap::new();
+        for _ in 0..r.len()? {
+            let (name, addr, cells) = (r.str()?, r.num()?, r.len()?);
+            if !(1..=2).contains(&cells) || !in_memory(addr, cells as i64 * cell) {
+                return Err(corrupt("value"));
+            }
+            values.insert(name, (addr, cells));
+        }
+        let mut deferred = HashMap::new();
+        for _ in 0..r.len()? {
+            deferred.insert(r.str()?, r.num()?);
+        }
+        let mut xts = vec![];
+        for _ in 0..r.len()? {
+            xts.push(r.op()?);
+        }
+        if deferred
+            .values()
+            .any(|xt| !(0..=xts.len() as i64).contains(xt))
+        {
+            return Err(corrupt("deferred word"));
+        }
+        let mut effects = HashMap::new();
+        for _ in 0..r.len()? {
+            effects.insert(r.str()?, r.effect()?);
+        }
+        let mut words: HashMap<ForthOp, ForthFunc> = self
+            .words
+            .iter()
+            .filter(|(_, func)| matches!(func, ForthFunc::Host(_)))
+            .map(|(op, func)| (op.clone(), func.clone()))
+            .collect();
+        for _ in 0..r.len()? {
+            words.insert(r.op()?, r.func()?);
+        }
+        self.cell_width = cell_width;
+        self.parser = ForthParser::with_position(position);
+        self.stack = stack;
+        self.memory = memory;
+        self.heap = heap;
+        self.variables = variables;
+        self.values = values;
+        self.deferred = deferred;
+        self.xts = xts;
+        self.effects = effects;
+        self.words = words;
+        Ok(())
+    }
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> ForthResult<()> {
+        let src = fs::read_to_string(path)
+            .map_err(|e| ForthErr::Msg(format!("Cannot read file: {}", e)))?;
+        self.eval_str(&src)
+    }
+    pub fn eval_str(&mut self, expr: &str) -> ForthResult<()> {
+        // Limits apply to the outermost call, not to evaluations nested in host natives.
+        if self.eval_nesting == 0 {
+            self.steps = 0;
+            self.call_depth = 0;
+            self.deadline = self.limits.timeout.map(|t| Instant::now() + t);
+            // Only cancels that come in while the line runs stop it.
+            self.cancel.reset();
+        }
+        self.eval_nesting += 1;
+        let res = if self.transactional {
+            let mut snapshot = self.clone();
+            snapshot.leak_report = false;
+            let res = self.run_str(expr);
+            if res.is_err() {
+                // The abandoned state is dropped without reporting its blocks.
+                snapshot.leak_report = self.leak_report;
+                std::mem::replace(self, snapshot).leak_report = false;
+            }
+            res
+        } else {
+            self.run_str(expr)
+        };
+        self.eval_nesting -= 1;
+        res
+    }
+    // String literals are copied into data space once, when their line is
+    // compiled, and leave the same ( c-addr u ) each time they run.
+    fn place_strings(&mut self, res: &mut ParserResult) -> ForthResult<()> {
+        let mut placed = HashMap::new();
+        for text in &res.strings {
+            if !placed.contains_key(text) {
+                let len = text.len() as i64;
+                let addr = self.allot(len)?;
+                self.mem_mut(addr, len)?.copy_from_slice(text.as_bytes());
+                placed.insert(text.clone(), (addr, len));
+            }
+        }
+        let place = |code: &[ForthExp]| -> Vec<ForthExp> {
+            let mut placed_code = Vec::with_capacity(code.len());
+            for exp in code {
+                match exp {
+                    ForthExp::Op(ForthOp::SQuote(text)) => {
+                        let (addr, len) = placed[text];
+                        placed_code.extend([ForthExp::Number(addr), ForthExp::Number(len)]);
                     }
-                    ForthFunc::SetVar(name) => {
-                        let num = self.pop_num()?;
-                        self.variables.insert(name, num);
+                    exp => placed_code.push(exp.clone()),
+                }
+            }
+            placed_code
+        };
+        res.program = place(&res.program);
+        for def in res.definitions.iter_mut() {
+            def.body = place(&def.body).into();
+        }
+        for func in res.new_words.values_mut() {
+            match func {
+                ForthFunc::BeginUntil(body) => *body = place(body).into(),
+                ForthFunc::BeginWhile(head, body) => {
+                    *head = place(head).into();
+                    *body = place(body).into();
+                }
+                ForthFunc::IfThenElse((then, r#else)) => {
+                    *then = place(then).into();
+                    if let Some(r#else) = r#else {
+                        *r#else = place(r#else).into();
                     }
-                    ForthFunc::BeginUntil(v) => {
-                        let mut a = 0;
-                        while a == 0 {
-                            for e in &v {
-                                self.eval(e.clone())?;
-                            }
-                            a = self.pop_num()?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    // A definition keeps the values of the constants it uses, even if they
+    // are defined again later.
+    fn bind_constants(&mut self, name: &str, code: &[ForthExp]) -> Body {
+        let mut bound = Vec::with_capacity(code.len());
+        for exp in code {
+            match exp {
+                ForthExp::Op(op @ ForthOp::UserWord(word)) if word != name => {
+                    match self.words.get(op) {
+                        Some(ForthFunc::Constant(n)) => bound.push(ForthExp::Number(*n)),
+                        Some(ForthFunc::TwoConstant(n1, n2)) => {
+                            bound.extend([ForthExp::Number(*n1), ForthExp::Number(*n2)])
                         }
+                        _ => bound.push(exp.clone()),
                     }
-                    ForthFunc::BeginWhile(head, body) => loop {
-                        for e in &head {
-                            self.eval(e.clone())?;
-                        }
-                        let a = self.pop_num()?;
-                        if a == 0 {
-                            break;
+                }
+                ForthExp::Op(
+                    op @ (ForthOp::IfThenElse(_) | ForthOp::BeginUntil(_) | ForthOp::BeginWhile(_)),
+                ) => {
+                    let func = match self.words.get(op).cloned() {
+                        Some(ForthFunc::IfThenElse((then, r#else))) => {
+                            let then = self.bind_constants(name, &then);
+                            let r#else = r#else.map(|body| self.bind_constants(name, &body));
+                            ForthFunc::IfThenElse((then, r#else))
                         }
-                        for e in &body {
-                            self.eval(e.clone())?;
+                        Some(ForthFunc::BeginUntil(body)) => {
+                            ForthFunc::BeginUntil(self.bind_constants(name, &body))
                         }
-                    },
+                        Some(ForthFunc::BeginWhile(head, body)) => ForthFunc::BeginWhile(
+                            self.bind_constants(name, &head),
+                            self.bind_constants(name, &body),
+                        ),
+                        _ => continue,
+                    };
+                    self.words.insert(op.clone(), func);
+                    bound.push(exp.clone());
                 }
+                exp => bound.push(exp.clone()),
             }
-            ForthExp::Number(a) => self.push(ForthExp::Number(a)),
         }
+        bound.into()
+    }
+    // Compiles a colon definition against the dictionary as it stands.
+    fn define(&mut self, res: &ParserResult, def: &Definition) -> ForthResult<()> {
+        self.check_names(res, &def.body, Some(&def.name))?;
+        let op = ForthOp::UserWord(def.name.clone());
+        if !self.words.contains_key(&op) {
+            self.check_words(1)?;
+        }
+        let body = self.bind_constants(&def.name, &def.body);
+        self.check_effects(&def.name, &body, def.effect)?;
+        self.words.insert(op, ForthFunc::User(body));
         Ok(())
     }
-    pub fn eval_str(&mut self, expr: &str) -> ForthResult<()> {
-        let res = self.parser.parse_str(expr)?;
-        self.words.extend(res.new_words);
-        self.variables.extend(res.variables);
-        for st in res.program {
+    fn run(&mut self, res: &ParserResult, code: &[ForthExp]) -> ForthResult<()> {
+        self.check_names(res, code, None)?;
+        for st in code {
             self.eval(st)?;
         }
-
         Ok(())
     }
+    // A line runs in source order, so each definition sees what the code
+    // before it defined.
+    fn run_str(&mut self, expr: &str) -> ForthResult<()> {
+        let mut res = self.parser.parse_str(expr)?;
+        let code: Vec<&ForthExp> = res
+            .program
+            .iter()
+            .chain(res.definitions.iter().flat_map(|def| def.body.iter()))
+            .chain(res.new_words.values().flat_map(func_bodies).flatten())
+            .collect();
+        self.check_capabilities(code.iter().copied())?;
+        if self.legacy_invert {
+            self.warn_legacy(&code);
+        }
+        if !res.strings.is_empty() {
+            self.place_strings(&mut res)?;
+        }
+        self.words.extend(
+            res.new_words
+                .iter()
+                .map(|(op, func)| (op.clone(), func.clone())),
+        );
+        let mut done = 0;
+        for def in &res.definitions {
+            self.run(&res, &res.program[done..def.at])?;
+            done = def.at;
+            self.define(&res, def)?;
+        }
+        self.run(&res, &res.program[done..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    const PRIMITIVES: &[&str] = &[
+        "+",
+        "-",
+        "*",
+        "/",
+        "emit",
+        "type",
+        "1+",
+        "1-",
+        "2+",
+        "2-",
+        "2*",
+        "2/",
+        "mod",
+        "/mod",
+        "*/",
+        "*/mod",
+        "abs",
+        "negate",
+        "dup",
+        "drop",
+        "over",
+        "rot",
+        "swap",
+        "pick",
+        "roll",
+        ".",
+        "and",
+        "or",
+        "xor",
+        "not",
+        "<",
+        "=",
+        ">",
+        "<=",
+        ">=",
+        "<>",
+        "0<",
+        "0=",
+        "0>",
+        "0<>",
+        "invert",
+        "true",
+        "false",
+        "?dup",
+        "2dup",
+        "2drop",
+        "2swap",
+        "2over",
+        "depth",
+        "lshift",
+        "rshift",
+        "max",
+        "min",
+        "@",
+        "!",
+        "+!",
+        "c@",
+        "c!",
+        "here",
+        "allot",
+        ",",
+        "c,",
+        "cells",
+        "cell+",
+        "chars",
+        "char+",
+        "execute",
+        "defer@",
+        "defer!",
+        "compare",
+        "search",
+        "/string",
+        "-trailing",
+        "blank",
+        "move",
+        "cmove",
+        "cmove>",
+        "fill",
+        "erase",
+        "s+",
+        "allocate",
+        "free",
+        "resize",
+        "2@",
+        "2!",
+        "environment?",
+    ];
+
+    const WIDTHS: &[CellWidth] = &[CellWidth::Bits16, CellWidth::Bits32, CellWidth::Bits64];
+
+    // xorshift64*, so the suite stays deterministic and dependency free.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn cell(&mut self, width: CellWidth) -> i64 {
+            let edge = [
+                0,
+                1,
+                -1,
+                2,
+                width.bits() as i64,
+                width.wrap(i64::MAX),
+                width.wrap(i64::MIN),
+                width.wrap(1 << (width.bits() - 1)),
+                i64::MAX,
+                i64::MIN,
+            ];
+            match self.below(3) {
+                0 => edge[self.below(edge.len() as u64) as usize],
+                1 => self.below(64) as i64 - 32,
+                _ => self.next() as i64,
+            }
+        }
+    }
+
+    #[test]
+    fn primitives_never_panic() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for &width in WIDTHS {
+            for word in PRIMITIVES {
+                for _ in 0..300 {
+                    let mut interp = ForthInterp::with_cell_width(width);
+                    interp.allot(64).ok();
+                    for _ in 0..rng.below(6) {
+                        let n = rng.cell(width);
+                        interp.push_num(n).unwrap();
+                    }
+                    let before = interp.stack().to_vec();
+                    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                        interp.eval_str(word).ok();
+                    }));
+                    assert!(
+                        res.is_ok(),
+                        "{} panicked on {:?} with {}-bit cells",
+                        word,
+                        before,
+                        width.bits()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn results_fit_the_cell_width() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for &width in WIDTHS {
+            for word in PRIMITIVES {
+                for _ in 0..100 {
+                    let mut interp = ForthInterp::with_cell_width(width);
+                    for _ in 0..4 {
+                        interp.push_num(rng.cell(width)).unwrap();
+                    }
+                    interp.eval_str(word).ok();
+                    for &n in interp.stack() {
+                        assert_eq!(n, width.wrap(n), "{} left {} out of range", word, n);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn overflow_cases_wrap() {
+        let cases = [
+            ("-9223372036854775808 abs", i64::MIN),
+            ("-9223372036854775808 negate", i64::MIN),
+            ("-9223372036854775808 -1 /", i64::MIN),
+            ("-9223372036854775808 -1 mod", 0),
+            ("1 64 lshift", 0),
+            ("-1 100 rshift", 0),
+            ("1 -1 lshift", 0),
+            ("9223372036854775807 2 4 */", 4611686018427387903),
+        ];
+        for (src, expected) in cases.iter() {
+            let mut interp = ForthInterp::new();
+            assert!(interp.eval_str(src).is_ok(), "{} failed", src);
+            assert_eq!(interp.pop_num().ok(), Some(*expected), "{}", src);
+        }
+    }
+
+    #[test]
+    fn execution_tokens() {
+        let mut interp = ForthInterp::new();
+        assert!(interp.eval_str("3 ' dup execute").is_ok());
+        assert_eq!(interp.stack(), vec![3, 3]);
+
+        let mut interp = ForthInterp::new();
+        let src = ": sq dup * ; : apply execute ; 4 ['] sq apply";
+        assert!(interp.eval_str(src).is_ok());
+        assert_eq!(interp.stack(), vec![16]);
+
+        assert!(interp.eval_str("' nosuchword").is_err());
+        assert!(interp.eval_str("0 execute").is_err());
+    }
+
+    #[test]
+    fn deferred_words() {
+        let mut interp = ForthInterp::new();
+        assert!(interp.eval_str("defer hook : run 5 hook ;").is_ok());
+        assert!(interp.eval_str("run").is_err());
+        interp.clear_stack();
+        assert!(interp.eval_str(": twice 2 * ; ' twice is hook run").is_ok());
+        assert_eq!(interp.stack(), vec![10]);
+        assert!(interp.eval_str("' negate ' hook defer! run").is_ok());
+        assert_eq!(interp.stack(), vec![10, -5]);
+        assert!(interp.eval_str("action-of hook ' hook defer@ =").is_ok());
+        assert_eq!(interp.pop_num().ok(), Some(TRUE));
+        assert!(interp.eval_str("' dup is run").is_err());
+
+        // Chains of deferred words are followed; circles are errors, not a
+        // native stack overflow.
+        interp.clear_stack();
+        interp
+            .eval_str("defer outer ' hook is outer 3 outer")
+            .unwrap();
+        assert_eq!(interp.stack(), vec![-3]);
+        interp.set_limits(Limits {
+            max_steps: Some(1000),
+            max_return_depth: Some(100),
+            ..Limits::default()
+        });
+        assert!(interp.eval_str("defer foo ' foo is foo foo").is_err());
+        assert!(interp
+            .eval_str("defer ping defer pong ' pong is ping ' ping is pong ping")
+            .is_err());
+        assert!(interp.eval_str("' foo execute").is_err());
+    }
+
+    #[test]
+    fn host_natives() {
+        use std::cell::RefCell;
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut interp = ForthInterp::new();
+        let sink = log.clone();
+        interp.register_native("Record", move |interp| {
+            let n = interp.pop_num()?;
+            sink.borrow_mut().push(n);
+            Ok(())
+        });
+        interp.register_native("clamp", |interp| {
+            let hi = interp.pop_num()?;
+            let lo = interp.pop_num()?;
+            let n = interp.pop_num()?;
+            interp.push_num(n.max(lo).min(hi))
+        });
+        interp.register_native("even?", |interp| {
+            let n = interp.pop_num()?;
+            interp.push_bool(n % 2 == 0)
+        });
+
+        assert!(interp
+            .eval_str(": go 1 record 42 0 10 clamp record ; go")
+            .is_ok());
+        assert_eq!(*log.borrow(), vec![1, 10]);
+        assert!(interp.eval_str("4 even? 3 ' even? execute").is_ok());
+        assert_eq!(interp.stack(), vec![TRUE, FALSE]);
+        interp.clear_stack();
+        assert!(interp.eval_str("record").is_err());
+    }
+
+    #[test]
+    fn transactional_rollback() {
+        let mut interp = ForthInterp::new();
+        interp.set_transactional(true);
+        assert!(interp.eval_str("variable x 7 x ! 1 2").is_ok());
+        let here = interp.memory.len();
+
+        let src = ": sq dup * ; variable y 99 x ! 5 constant five 3 sq 0 /";
+        assert!(interp.eval_str(src).is_err());
+        assert_eq!(interp.stack(), vec![1, 2]);
+        assert_eq!(interp.variable("x"), Some(7));
+        assert_eq!(interp.variable("y"), None);
+        assert_eq!(interp.memory.len(), here);
+        assert!(interp.eval_str("sq").is_err());
+        assert!(interp.eval_str("five").is_err());
+
+        interp.set_transactional(false);
+        assert!(interp.eval_str("3 x ! 0 0 /").is_err());
+        assert_eq!(interp.variable("x"), Some(3));
+    }
+
+    #[test]
+    fn execution_limits() {
+        let mut interp = ForthInterp::new();
+        interp.set_limits(Limits {
+            max_steps: Some(10_000),
+            max_stack_depth: Some(100),
+            max_return_depth: Some(50),
+            max_words: Some(3),
+            max_data_space: Some(64),
+            timeout: None,
+        });
+        let cases = [
+            ("begin 0 until", Limit::Steps),
+            ("begin 1 dup 0 until", Limit::StackDepth),
+            (": down 1 - dup if down then ; 100 down", Limit::ReturnDepth),
+            (": a ; : b ; : c ; : d ;", Limit::Words),
+            ("100 allot", Limit::DataSpace),
+            ("1 constant c1 2 constant c2 3 4 2constant c3", Limit::Words),
+        ];
+        for (src, limit) in cases.iter() {
+            assert_eq!(
+                interp.eval_str(src),
+                Err(ForthErr::LimitExceeded(*limit)),
+                "{}",
+                src
+            );
+            interp.clear_stack();
+            assert!(interp.eval_str("1 2 +").is_ok());
+            assert_eq!(interp.pop_num().ok(), Some(3));
+        }
+        assert!(interp.eval_str("10 down").is_ok());
+
+        interp.set_limits(Limits {
+            max_stack_depth: Some(100),
+            ..Limits::default()
+        });
+        interp.register_native("flood", |interp| {
+            for n in 0..1_000_000 {
+                interp.push_num(n)?;
+            }
+            Ok(())
+        });
+        assert_eq!(
+            interp.eval_str("1 2 flood"),
+            Err(ForthErr::LimitExceeded(Limit::StackDepth))
+        );
+        assert!(interp.stack().is_empty());
+
+        interp.set_limits(Limits {
+            timeout: Some(std::time::Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        assert_eq!(
+            interp.eval_str("begin 0 until"),
+            Err(ForthErr::LimitExceeded(Limit::Timeout))
+        );
+        assert!(interp.eval_str("5").is_ok());
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let mut interp = ForthInterp::new();
+        interp
+            .eval_str(
+                ": down dup if 1- down then ;
+                 defer again : down2 dup if 1- action-of again execute then ; ' down2 is again
+                 50000 down 50000 down2",
+            )
+            .unwrap();
+        assert_eq!(interp.stack(), [0, 0]);
+
+        // Runaway recursion stops at the default return depth.
+        interp.clear_stack();
+        assert_eq!(
+            interp.eval_str(": forever forever ; forever"),
+            Err(ForthErr::LimitExceeded(Limit::ReturnDepth))
+        );
+        assert!(interp.eval_str("1000 down").is_ok());
+        assert_eq!(interp.stack(), [0]);
+    }
+
+    #[test]
+    fn capabilities_are_checked_at_compile_time() {
+        let mut interp = ForthInterp::new();
+        interp.register_native("secret", |interp| interp.push_num(42));
+        interp.set_capabilities(Capabilities::none());
+        let forbidden = [
+            ("secret", Capability::HostNatives),
+            (": f 1 if secret then ; 0 f", Capability::HostNatives),
+            ("' secret execute", Capability::HostNatives),
+            (": g begin key 0 until ;", Capability::ConsoleInput),
+            ("here 10 accept", Capability::ConsoleInput),
+            ("s\" max-n\" environment?", Capability::Environment),
+        ];
+        for (src, cap) in forbidden.iter() {
+            match interp.eval_str(src) {
+                Err(ForthErr::Forbidden(word, c)) => {
+                    assert_eq!(c, *cap, "{}", src);
+                    assert!(src.contains(&word), "{} reported as {}", src, word);
+                }
+                _ => panic!("{} was not rejected", src),
+            }
+            assert!(
+                interp.stack().is_empty(),
+                "{} ran before being rejected",
+                src
+            );
+        }
+        assert!(interp.eval_str("f").is_err());
+        assert!(interp.eval_str("1 2 + .").is_ok());
+
+        // Execution tokens and deferred words from before the restriction
+        // are checked when they run.
+        let mut interp = ForthInterp::new();
+        interp.register_native("secret", |interp| interp.push_num(42));
+        interp
+            .eval_str("' open-file drop defer hook ' secret is hook : opener open-file ;")
+            .unwrap();
+        interp.set_capabilities(Capabilities::none());
+        let forbidden = [
+            ("0 0 0 1 execute", Capability::FileAccess),
+            ("hook", Capability::HostNatives),
+            ("action-of hook execute", Capability::HostNatives),
+            ("0 0 0 opener", Capability::FileAccess),
+        ];
+        for (src, cap) in forbidden.iter() {
+            match interp.eval_str(src) {
+                Err(ForthErr::Forbidden(_, c)) => assert_eq!(c, *cap, "{}", src),
+                res => panic!("{} was not rejected: {:?}", src, res),
+            }
+            interp.clear_stack();
+        }
+        assert!(interp.stack().is_empty());
+
+        interp.set_capabilities(Capabilities {
+            host_natives: true,
+            ..Capabilities::none()
+        });
+        assert!(interp.eval_str("secret").is_ok());
+        assert_eq!(interp.stack(), vec![42]);
+    }
+
+    #[test]
+    fn cancellation_interrupts_running_code() {
+        use std::thread;
+        use std::time::Duration;
+
+        let mut interp = ForthInterp::new();
+        let handle = interp.cancel_handle();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.cancel();
+        });
+        assert_eq!(
+            interp.eval_str(": spin begin 0 until ; spin"),
+            Err(ForthErr::Interrupted)
+        );
+        canceller.join().unwrap();
+        assert!(!interp.cancel_handle().is_cancelled());
+        assert!(interp.eval_str("2 3 *").is_ok());
+        assert_eq!(interp.stack(), vec![6]);
+
+        // A cancel that arrives between lines doesn't stop the next one.
+        interp.cancel_handle().cancel();
+        assert!(interp.eval_str("1").is_ok());
+        assert_eq!(interp.stack(), vec![6, 1]);
+    }
+
+    #[test]
+    fn tracer_sees_every_word() {
+        struct Log(Rc<RefCell<Vec<String>>>);
+
+        impl Tracer for Log {
+            fn before(&mut self, _: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+                self.0
+                    .borrow_mut()
+                    .push(format!("{}{}", step.depth, step.word));
+                Ok(())
+            }
+            fn after(&mut self, interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+                if step.call {
+                    self.0.borrow_mut().push(format!("{:?}", interp.stack()));
+                }
+                Ok(())
+            }
+        }
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut interp = ForthInterp::new();
+        interp.set_tracer(Some(Rc::new(RefCell::new(Log(log.clone())))));
+        assert!(interp.eval_str(": sq dup * ; 3 sq").is_ok());
+        assert_eq!(*log.borrow(), vec!["03", "0sq", "1dup", "1*", "[9]"]);
+
+        // Built-in words are shown as they are spelled in Forth.
+        log.borrow_mut().clear();
+        assert!(interp.eval_str("2 3 * . 1+ 0<").is_ok());
+        assert_eq!(*log.borrow(), vec!["02", "03", "0*", "0.", "01+", "00<"]);
+        for op in image::SIMPLE_OPS {
+            assert!(parser::word_of(op).is_some(), "{} has no name", op);
+        }
+    }
+
+    #[test]
+    fn debugger_steps_and_breaks() {
+        use super::super::debug::Debugger;
+        use std::io::Cursor;
+
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let script = "st\nn\nst\no\nq\n";
+        let out = Rc::new(RefCell::new(Vec::new()));
+
+        let mut debugger = Debugger::new(
+            Box::new(Cursor::new(script.as_bytes().to_vec())),
+            Box::new(Shared(out.clone())),
+        );
+        debugger.add_breakpoint("sq");
+        let debugger = Rc::new(RefCell::new(debugger));
+        let mut interp = ForthInterp::new();
+        interp.set_tracer(Some(debugger.clone()));
+
+        let res = interp.eval_str(": sq dup * ; : quad sq sq ; 2 quad 1");
+        assert_eq!(res, Err(ForthErr::Interrupted));
+        let out = String::from_utf8(out.borrow().clone()).unwrap();
+        let expected = "sq at depth 1 [ 2 ]\ndbg> [ 2 ]\n\
+                        dbg> sq at depth 1 [ 4 ]\ndbg> [ 4 ]\n\
+                        dbg> 1 at depth 0 [ 16 ]\ndbg> ";
+        assert_eq!(out, expected);
+        assert_eq!(interp.stack(), vec![16]);
+        assert!(interp.eval_str("quad").is_ok());
+        assert_eq!(interp.stack(), vec![65536]);
+    }
+
+    #[test]
+    fn profiler_counts_calls() {
+        use super::super::profile::Profiler;
+
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let mut interp = ForthInterp::new();
+        interp.set_tracer(Some(profiler.clone()));
+        let res =
+            interp.eval_str(": sq dup * ; : fact dup 1 > if dup 1- fact * then ; 3 sq 4 fact");
+        assert!(res.is_ok());
+        let profiler = profiler.borrow();
+        let calls = |word: &str| {
+            let entry = profiler.entries().into_iter().find(|e| e.word == word);
+            entry.map_or(0, |e| e.calls)
+        };
+        assert_eq!(calls("sq"), 1);
+        assert_eq!(calls("fact"), 4);
+        assert_eq!(calls("dup"), 8);
+        assert_eq!(calls("(literal)"), 6);
+        assert_eq!(calls("*"), 4);
+        assert_eq!(calls("1-"), 3);
+        for entry in profiler.entries() {
+            assert!(entry.exclusive <= entry.inclusive);
+        }
+
+        let mut folded = vec![];
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded
+            .lines()
+            .any(|l| l.starts_with("fact;if;fact;if;fact;if;fact;dup ")));
+        assert!(folded.lines().any(|l| l.starts_with("sq;* ")));
+        assert!(profiler.report().lines().any(|l| l.starts_with("sq ")));
+        assert!(profiler.report().lines().any(|l| l.starts_with("* ")));
+    }
+
+    #[test]
+    fn stack_effects_are_checked() {
+        let mut interp = ForthInterp::new();
+        let src = "
+            : sq ( n -- n*n ) dup * ;        \\ not a plain picture, not checked
+            : sum3 ( a b c -- sum ) + + ;
+            : bad ( a b -- c ) + + ;
+            : odd 0< if 1 then ;
+            : count-down ( n -- ) begin 1- dup 0= until drop ;
+            : max3 ( a b c -- m ) max max ;
+            : uses ( a b c -- m ) sum3 ;
+        ";
+        assert!(interp.eval_str(src).is_ok());
+        assert_eq!(
+            interp.take_warnings(),
+            vec![
+                "bad: stack effect is ( 3 -- 1 ) but the comment declares ( 2 -- 1 )",
+                "odd: IF branches leave different stack depths (+1 and +0)",
+            ]
+        );
+        let effect = |inputs, outputs| Some(StackEffect { inputs, outputs });
+        assert_eq!(interp.stack_effect("sq"), effect(1, 1));
+        assert_eq!(interp.stack_effect("count-down"), effect(1, 0));
+        assert_eq!(interp.stack_effect("odd"), None);
+
+        interp.set_strict_effects(true);
+        assert!(interp.eval_str(": worse ( a -- ) dup ;").is_err());
+        assert_eq!(interp.stack_effect("worse"), None);
+        assert!(interp
+            .eval_str(": fine ( a -- ) ( ignored ) drop ;")
+            .is_ok());
+    }
+
+    #[test]
+    fn images_restore_state() {
+        let mut interp = ForthInterp::with_cell_width(CellWidth::Bits32);
+        interp.register_native("answer", |interp| interp.push_num(42));
+        let src = "variable x 7 x ! 10 constant ten defer hook
+            : sq ( n -- n ) dup * ; ' sq is hook
+            : f dup 0> if drop ten else begin 1+ dup 0= until then ; 3 4";
+        assert!(interp.eval_str(src).is_ok());
+        assert!(interp
+            .eval_str(&format!(": all {} ;", PRIMITIVES.join(" ")))
+            .is_ok());
+        let mut image = vec![];
+        assert!(interp.write_image(&mut image).is_ok());
+
+        let mut loaded = ForthInterp::new();
+        loaded.register_native("answer", |interp| interp.push_num(42));
+        assert!(loaded.read_image(&mut image.as_slice()).is_ok());
+        assert_eq!(loaded.cell_width(), CellWidth::Bits32);
+        assert_eq!(loaded.stack(), vec![3, 4]);
+        assert_eq!(loaded.variable("x"), Some(7));
+        assert_eq!(loaded.stack_effect("sq"), interp.stack_effect("sq"));
+        loaded.clear_stack();
+        assert!(loaded
+            .eval_str("ten 5 hook 1 f -2 f answer : g if 1 then ; 0 g")
+            .is_ok());
+        assert_eq!(loaded.stack(), vec![10, 25, 10, 0, 42]);
+
+        let mut bad = image.clone();
+        bad[6] = 99;
+        assert!(loaded.read_image(&mut bad.as_slice()).is_err());
+        assert!(loaded.read_image(&mut &image[..image.len() - 1]).is_err());
+        assert!(loaded.read_image(&mut &b"#!/bin/sh"[..]).is_err());
+        assert_eq!(loaded.stack(), vec![10, 25, 10, 0, 42]);
+    }
+
+    #[test]
+    fn images_are_checked_when_loaded() {
+        type Block = (i64, usize);
+        let image = |stack: &[i64], memory: usize, heap: &[Block], var: i64, value: Block, xt| {
+            let mut out = vec![];
+            let mut w = ImageWriter::new(&mut out);
+            w.header()?;
+            w.len(16)?;
+            w.len(0)?;
+            w.len(stack.len())?;
+            for num in stack {
+                w.num(*num)?;
+            }
+            w.blob(&vec![0; memory])?;
+            w.len(heap.len())?;
+            for (addr, size) in heap {
+                w.num(*addr)?;
+                w.blob(&vec![0; *size])?;
+            }
+            w.len(1)?;
+            w.str("v")?;
+            w.num(var)?;
+            w.len(1)?;
+            w.str("w")?;
+            w.num(value.0)?;
+            w.len(value.1)?;
+            w.len(1)?;
+            w.str("d")?;
+            w.num(xt)?;
+            // No execution tokens, effects or words.
+            for _ in 0..3 {
+                w.len(0)?;
+            }
+            ForthResult::Ok(out)
+        };
+        let heap = [(1 << 14, 8), ((1 << 14) + 16, 8)];
+        let mut loaded = ForthInterp::new();
+        let good = image(&[-32768], 16, &heap, 8, (0, 2), 0).unwrap();
+        assert!(loaded.read_image(&mut good.as_slice()).is_ok());
+        assert_eq!(loaded.stack(), [-32768]);
+
+        let bad = [
+            image(&[32768], 16, &heap, 8, (0, 2), 0),
+            image(&[], (1 << 14) + 1, &heap, 8, (0, 2), 0),
+            image(&[], 16, &[(16, 8)], 8, (0, 2), 0),
+            image(&[], 16, &[(1 << 14, 8), ((1 << 14) + 4, 8)], 8, (0, 2), 0),
+            image(&[], 16, &[((1 << 15) - 4, 8)], 8, (0, 2), 0),
+            image(&[], 16, &heap, 15, (0, 2), 0),
+            image(&[], 16, &heap, -1, (0, 2), 0),
+            image(&[], 16, &heap, 8, (14, 2), 0),
+            image(&[], 16, &heap, 8, (0, 3), 0),
+            image(&[], 16, &heap, 8, (0, 2), 1),
+        ];
+        for (i, bad) in bad.iter().enumerate() {
+            let res = loaded.read_image(&mut bad.as_ref().unwrap().as_slice());
+            assert!(
+                matches!(res, Err(ForthErr::Msg(msg)) if msg.starts_with("Corrupt image")),
+                "{}",
+                i
+            );
+        }
+        assert_eq!(loaded.stack(), [-32768]);
+    }
+
+    #[test]
+    fn save_system_writes_an_image() {
+        let path = std::env::temp_dir().join(format!("rforth-{}.img", std::process::id()));
+        let mut interp = ForthInterp::new();
+        let src = format!(": five 5 ; save-system {}", path.display());
+        assert!(interp.eval_str(&src).is_ok());
+        let mut loaded = ForthInterp::new();
+        assert!(loaded.load_image(&path).is_ok());
+        assert!(loaded.eval_str("five").is_ok());
+        assert_eq!(loaded.stack(), vec![5]);
+        let _ = fs::remove_file(&path);
+
+        interp.set_capabilities(Capabilities::none());
+        assert_eq!(
+            interp.eval_str("save-system x"),
+            Err(ForthErr::Forbidden(
+                "save-system x".to_string(),
+                Capability::FileAccess
+            ))
+        );
+    }
+
+    #[test]
+    fn hayes_tester_reports_failures() {
+        let mut interp = ForthInterp::new();
+        let src = "7
+            T{ 1 2 + -> 3 }T
+            : sq dup * ;
+            T{ 3 sq -> 10 }T   \\ wrong on purpose
+            T{ -> }T
+            T{ 1 2 -> 1 }T";
+        assert!(interp.eval_str(src).is_ok());
+        assert_eq!(interp.stack(), vec![7]);
+        let results = interp.take_test_results();
+        assert_eq!(results.passed, 2);
+        assert_eq!(
+            results.failures,
+            vec![
+                TestFailure {
+                    line: 4,
+                    expected: vec![10],
+                    actual: vec![9],
+                },
+                TestFailure {
+                    line: 6,
+                    expected: vec![1],
+                    actual: vec![1, 2],
+                },
+            ]
+        );
+        assert_eq!(
+            results.failures[0].to_string(),
+            "line 4: expected [ 10 ] but got [ 9 ]"
+        );
+        assert!(interp.eval_str("1 }t").is_err());
+        assert!(interp.eval_str("t{ 1 }t").is_err());
+    }
+
+    #[test]
+    fn invert_is_bitwise() {
+        let mut interp = ForthInterp::new();
+        interp.eval_str("0 invert 5 invert 0 not 5 not").unwrap();
+        assert_eq!(interp.stack(), [-1, -6, -1, 0]);
+        assert!(interp.take_warnings().is_empty());
+
+        let mut interp = ForthInterp::new();
+        interp.set_legacy_invert(true);
+        interp.eval_str(": f 5 invert ; f 5 not").unwrap();
+        assert_eq!(interp.stack(), [0, -6]);
+        let warnings = interp.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("INVERT"));
+        assert!(warnings[1].contains("NOT"));
+        interp.eval_str("1 2 +").unwrap();
+        assert!(interp.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn stack_display_words() {
+        let mut interp = ForthInterp::new();
+        interp.eval_str("1 2 .s variable v 42 v ! v ?").unwrap();
+        assert_eq!(interp.stack(), [1, 2]);
+        assert_eq!(interp.format_stack(), "<2> 1 2");
+        assert!(interp.eval_str("-1 ?").is_err());
+        assert_eq!(interp.stack(), [1, 2, -1]);
+
+        interp.eval_str("here 72 c, 105 c, 0 c,").unwrap();
+        let addr = interp.pop_num().unwrap();
+        assert_eq!(
+            interp.hex_dump(addr, 3).unwrap(),
+            format!("{:08x}: 48 69 00{}Hi.\n", addr, " ".repeat(41))
+        );
+        assert_eq!(interp.hex_dump(addr, 0).unwrap(), "");
+        assert!(interp.eval_str(&format!("{} 100 dump", addr)).is_err());
+    }
+
+    #[test]
+    fn undefined_words_are_rejected_when_compiled() {
+        let mut interp = ForthInterp::new();
+        let err = interp.eval_str(": f 0 if fooo then ;").unwrap_err();
+        assert_eq!(err.to_string(), "fooo ?");
+        assert!(interp.stack_effect("f").is_none());
+        assert_eq!(
+            interp.eval_str(": a b ; : b 1 ;").unwrap_err().to_string(),
+            "b ?"
+        );
+        assert_eq!(interp.eval_str("' nope").unwrap_err().to_string(), "nope ?");
+        for src in [": s ['] dup is nope ;", ": t action-of nope ;"] {
+            assert_eq!(interp.eval_str(src).unwrap_err().to_string(), "nope ?");
+        }
+        // Only what the line defined before a definition counts.
+        assert_eq!(
+            interp
+                .eval_str(": h later ; 5 constant later")
+                .unwrap_err()
+                .to_string(),
+            "later ?"
+        );
+        interp.eval_str("5 constant sooner : h sooner ; h").unwrap();
+        assert_eq!(interp.stack(), [5]);
+        interp.clear_stack();
+
+        // DEFER makes a forward reference possible, and a definition can
+        // call itself.
+        interp
+            .eval_str("defer b : a b 1+ ; : b' 41 ; ' b' is b a")
+            .unwrap();
+        assert_eq!(interp.stack(), [42]);
+        interp.eval_str(": g dup 0> if 1- g then ; 3 g").unwrap();
+        assert_eq!(interp.stack(), [42, 0]);
+    }
+
+    #[test]
+    fn character_literals() {
+        let mut interp = ForthInterp::new();
+        interp
+            .eval_str("char A char abc : f [char] Z ; f 'a' 'λ' char ( char \\")
+            .unwrap();
+        assert_eq!(interp.stack(), [65, 97, 90, 97, 955, 40, 92]);
+        assert!(interp.eval_str("char").is_err());
+        assert!(interp.eval_str("'ab'").is_err());
+    }
+
+    #[test]
+    fn string_words() {
+        let mut interp = ForthInterp::new();
+        interp.eval_str("s\" abc \" drop constant s s").unwrap();
+        let s = interp.pop_num().unwrap();
+        let cases = [
+            ("s 3 s 4 compare", vec![-1]),
+            ("s 4 s 3 compare", vec![1]),
+            ("s 2 s 2 compare", vec![0]),
+            ("s 4 -trailing", vec![s, 3]),
+            ("s 4 s 1+ 2 search", vec![s + 1, 3, -1]),
+            ("s 3 s 3 + 1 search", vec![s, 3, 0]),
+            ("s 4 1 /string", vec![s + 1, 3]),
+            ("s s 1+ 2 cmove s 2 + c@", vec!['a' as i64]),
+        ];
+        for (src, expected) in cases.iter() {
+            interp.clear_stack();
+            interp.eval_str(src).unwrap();
+            assert_eq!(interp.stack(), expected.as_slice(), "{}", src);
+        }
+        assert!(interp.eval_str("s 100 blank").is_err());
+        assert_eq!(interp.stack().len(), 3);
+
+        interp.clear_stack();
+        interp.eval_str("s 2 s 1 s+ s 1 s+ here").unwrap();
+        let (addr, len, here) = (interp.stack()[0], interp.stack()[1], interp.stack()[2]);
+        assert_eq!(len, 4);
+        assert_eq!(here, addr + 4);
+        let joined: Vec<i64> = (0..4)
+            .map(|i| interp.fetch_char(addr + i).unwrap())
+            .collect();
+        assert_eq!(joined, ['a' as i64; 4]);
+
+        // A literal is placed once and leaves the same string each time.
+        interp.clear_stack();
+        interp.eval_str(": hi s\" Hi \\ ( there\" ; hi hi").unwrap();
+        let hi = interp.stack()[0];
+        assert_eq!(interp.stack(), [hi, 12, hi, 12]);
+        let text: Vec<u8> = (0..12)
+            .map(|i| interp.fetch_char(hi + i).unwrap() as u8)
+            .collect();
+        assert_eq!(text, b"Hi \\ ( there");
+        assert_eq!(
+            interp.stack_effect("hi"),
+            Some(StackEffect {
+                inputs: 0,
+                outputs: 2
+            })
+        );
+        interp.eval_str(": greet .\" Hello\" ;").unwrap();
+        assert_eq!(
+            interp.stack_effect("greet"),
+            Some(StackEffect {
+                inputs: 0,
+                outputs: 0
+            })
+        );
+        assert!(interp.eval_str("s\" unterminated").is_err());
+        interp.eval_str("( s\" ) 1").unwrap();
+    }
+
+    #[test]
+    fn environment_queries() {
+        let mut interp = ForthInterp::with_cell_width(CellWidth::Bits16);
+        interp.set_limits(Limits {
+            max_stack_depth: Some(64),
+            ..Limits::default()
+        });
+        interp
+            .eval_str(
+                "s\" MAX-N\" environment? s\" max-u\" environment?
+                 s\" floored\" environment? s\" stack-cells\" environment?
+                 s\" return-stack-cells\" environment? s\" no-such\" environment?",
+            )
+            .unwrap();
+        assert_eq!(
+            interp.stack(),
+            [32767, -1, -1, -1, 0, -1, 64, -1, 32767, -1, 0]
+        );
+    }
+
+    #[test]
+    fn file_access_words() {
+        let path = std::env::temp_dir().join(format!("rforth-files-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut interp = ForthInterp::new();
+        let name = format!("s\" {}\"", path);
+        let buf = interp.allot(16).unwrap();
+        let src = format!(
+            "{name} w/o create-file drop constant out
+             s\" a,b\" out write-line s\" a,b\" out write-file out close-file
+             {name} r/o bin open-file drop constant in
+             in file-size drop drop
+             {buf} 16 in read-line {buf} 16 in read-line {buf} 16 in read-line
+             in file-position drop drop 0 0 in reposition-file
+             {buf} 2 in read-file in close-file in close-file",
+            name = name,
+            buf = buf
+        );
+        interp.eval_str(&src).unwrap();
+        assert_eq!(
+            interp.stack(),
+            [0, 0, 0, 7, 3, -1, 0, 3, -1, 0, 0, 0, 0, 7, 0, 2, 0, 0, -37]
+        );
+        assert_eq!(fs::read_to_string(path).unwrap(), "a,b\na,b");
+
+        interp.clear_stack();
+        interp
+            .eval_str(&format!("{} delete-file {} delete-file", name, name))
+            .unwrap();
+        assert_eq!(interp.stack(), [0, -38]);
+
+        interp.set_capabilities(Capabilities::none());
+        assert!(matches!(
+            interp.eval_str(&format!("{} r/o open-file", name)),
+            Err(ForthErr::Forbidden(_, Capability::FileAccess))
+        ));
+    }
+
+    #[test]
+    fn dynamic_memory_words() {
+        let mut interp = ForthInterp::new();
+        interp
+            .eval_str(
+                "16 allocate drop constant a 8 allocate drop constant b
+                 42 a ! 7 a 8 + c! a @ a 8 + c@",
+            )
+            .unwrap();
+        assert_eq!(interp.stack(), [42, 7]);
+        assert!(interp.eval_str("a 9 + @").is_err());
+        assert!(interp.eval_str("b 16 a 8 move").is_err());
+
+        // a can't grow in place with b right behind it, so it moves.
+        interp.clear_stack();
+        interp
+            .eval_str("a 64 resize swap constant a2 a2 @ a2 8 + c@ a2 a =")
+            .unwrap();
+        assert_eq!(interp.stack(), [0, 42, 7, 0]);
+        interp.clear_stack();
+        interp
+            .eval_str("a free a2 free a2 free -1 allocate swap drop b -1 resize")
+            .unwrap();
+        let b = (1 << 24) + 24;
+        assert_eq!(interp.stack(), [-60, 0, -60, -59, b, -61]);
+        assert!(interp.eval_str("a2 @").is_err());
+        assert_eq!(
+            interp.leak_report(),
+            Some(format!(
+                "1 heap blocks (8 bytes) were never freed: {} (8 bytes)",
+                b
+            ))
+        );
+        interp.eval_str("b free drop").unwrap();
+        assert_eq!(interp.leak_report(), None);
+
+        // Sizes near the top of the cell range fail with an ior even when
+        // other blocks are already in place.
+        interp.clear_stack();
+        interp
+            .eval_str(
+                "16 allocate drop 9223372036854775807 allocate swap drop
+                 16 allocate drop 9223372036854775807 resize swap drop",
+            )
+            .unwrap();
+        assert_eq!(interp.stack()[1..], [-59, -61]);
+    }
+
+    #[test]
+    fn locals() {
+        let mut interp = ForthInterp::new();
+        interp
+            .eval_str(
+                ": sum {: a b | c -- d :} a b + to c c ;
+                 : fib {: n :} n 2 < if n else n 1- fib n 2 - fib sum then ;
+                 : legacy locals| x y | x y - ;
+                 10 fib 1 5 legacy",
+            )
+            .unwrap();
+        assert_eq!(interp.stack(), [55, 4]);
+        assert_eq!(interp.locals, []);
+
+        interp.clear_stack();
+        interp.eval_str(": f {: a :} 5 +to a a ; 1 f").unwrap();
+        assert_eq!(interp.stack(), [6]);
+
+        assert!(interp.eval_str("{: a :}").is_err());
+        assert!(interp.eval_str(": twice {: a :} {: b :} ;").is_err());
+        assert!(interp.eval_str(": nope 1 to x ;").is_err());
+    }
+
+    #[test]
+    fn values_and_double_cell_words() {
+        let mut interp = ForthInterp::new();
+        // Each CONSTANT binds its name when it runs, so redefining works.
+        interp.eval_str("1 constant a 2 constant a a").unwrap();
+        assert_eq!(interp.stack(), [2]);
+
+        // A definition keeps the values the constants had when it was
+        // compiled.
+        interp.clear_stack();
+        interp
+            .eval_str(
+                "1 constant c 3 4 2constant d : g c d 1 if c then ;
+                 2 constant c 5 6 2constant d g c d",
+            )
+            .unwrap();
+        assert_eq!(interp.stack(), [1, 3, 4, 1, 2, 5, 6]);
+
+        interp.clear_stack();
+        interp
+            .eval_str(
+                "10 value v : bump 1 +to v ; 5 +to v bump v
+                 1 2 2value w 3 4 to w w
+                 2variable d 5 6 d 2! d 2@ d @
+                 7 8 2constant e e",
+            )
+            .unwrap();
+        assert_eq!(interp.stack(), [16, 3, 4, 5, 6, 6, 7, 8]);
+
+        assert_eq!(
+            interp.eval_str("1 to nope"),
+            Err(ForthErr::Msg("nope ?".to_string()))
+        );
+        assert!(interp.eval_str("1 to a").is_err());
+        assert!(interp.eval_str("1 +to w").is_err());
+    }
 }
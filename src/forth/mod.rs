@@ -1,6 +1,18 @@
-pub mod interp;
-pub mod parser;
-pub mod types;
+mod debug;
+mod effect;
+mod file;
+mod heap;
+mod image;
+mod interp;
+mod parser;
+mod profile;
+mod types;
 
+pub use self::debug::{Debugger, StackTracer, TraceStep, Tracer};
+pub use self::effect::StackEffect;
 pub use self::interp::ForthInterp;
-pub use self::types::ForthErr;
+pub use self::profile::{ProfileEntry, Profiler};
+pub use self::types::{
+    CancelHandle, Capabilities, Capability, CellWidth, ForthErr, ForthResult, Limit, Limits,
+    TestFailure, TestResults,
+};
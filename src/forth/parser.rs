@@ -1,7 +1,12 @@
+use super::effect::StackEffect;
 use super::types::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::num::ParseIntError;
 use std::slice::Iter;
+use std::sync::OnceLock;
+
+// Words whose argument is taken verbatim.
+const KEEP_CASE: &[&str] = &["save-system", "char", "[char]"];
 
 #[derive(Eq, PartialEq, Clone)]
 enum ParserState {
@@ -12,45 +17,115 @@ enum ParserState {
 
 #[derive(Clone)]
 pub struct ForthParser {
-    tokens: Vec<String>,
     cur: usize,
     state: ParserState,
     word_name: String,
     new_word: Vec<ForthExp>,
+    comment: Option<Vec<String>>,
+    declared: Option<StackEffect>,
+    // Names of the locals of the definition being compiled, by number.
+    locals: Option<Vec<String>>,
+    test_lines: VecDeque<usize>,
+}
+
+pub struct Definition {
+    pub name: String,
+    pub body: Body,
+    // The effect its comment declares.
+    pub effect: Option<StackEffect>,
+    // How much of the program comes before it.
+    pub at: usize,
 }
 
 pub struct ParserResult {
     pub program: Vec<ForthExp>,
+    // Control structures, keyed by the op that runs them.
     pub new_words: HashMap<ForthOp, ForthFunc>,
-    pub variables: HashMap<String, i64>,
+    // Colon definitions in source order.
+    pub definitions: Vec<Definition>,
+    // Text of the string literals in source order.
+    pub strings: Vec<String>,
 }
 
 impl ForthParser {
     pub fn new() -> ForthParser {
         ForthParser {
-            tokens: vec![],
             cur: 0,
             state: ParserState::Normal,
             word_name: String::new(),
             new_word: vec![],
+            comment: None,
+            declared: None,
+            locals: None,
+            test_lines: VecDeque::new(),
         }
     }
     pub fn parse_str(&mut self, expr: &str) -> ForthResult<ParserResult> {
-        let lower_expr = expr.to_ascii_lowercase();
-        let parsed_exp = self.parse(&tokenize(&lower_expr))?;
+        // Words are case-insensitive but file names and characters are not.
+        let mut tokens: Vec<(usize, String)> = vec![];
+        for (line, token) in tokenize(expr) {
+            match tokens.last() {
+                Some((_, prev)) if KEEP_CASE.contains(&prev.as_str()) => {
+                    tokens.push((line, token.to_string()))
+                }
+                _ if char_literal(token).is_some() => tokens.push((line, token.to_string())),
+                _ => match string_literal(token) {
+                    Some((word, text)) => {
+                        tokens.push((line, format!("{} {}", word.to_ascii_lowercase(), text)))
+                    }
+                    None => tokens.push((line, token.to_ascii_lowercase())),
+                },
+            }
+        }
+        let tokens: Vec<(usize, &str)> = tokens.iter().map(|(l, t)| (*l, t.as_str())).collect();
+        let parsed_exp = self.parse(&tokens)?;
 
         Ok(parsed_exp)
     }
-    fn parse(&mut self, tokens: &[&str]) -> ForthResult<ParserResult> {
+    // Position used to number control structures, kept across images so that
+    // their synthetic keys don't collide.
+    pub fn position(&self) -> usize {
+        self.cur
+    }
+    pub fn with_position(cur: usize) -> ForthParser {
+        ForthParser {
+            cur,
+            ..ForthParser::new()
+        }
+    }
+    fn parse(&mut self, tokens: &[(usize, &str)]) -> ForthResult<ParserResult> {
         let mut res = ParserResult {
             program: vec![],
             new_words: HashMap::new(),
-            variables: HashMap::new(),
+            definitions: vec![],
+            strings: vec![],
         };
         let mut normal_tokens = vec![];
         let mut word_tokens = vec![];
-        let mut iter = tokens.iter();
-        while let Some(token) = iter.next() {
+        self.test_lines.clear();
+        for (i, (line, token)) in tokens.iter().enumerate() {
+            if let Some(comment) = &mut self.comment {
+                if let Some(last) = token.strip_suffix(')') {
+                    if !last.is_empty() {
+                        comment.push(last.to_string());
+                    }
+                    let comment = self.comment.take().unwrap_or_default();
+                    if self.state == ParserState::WordBody && word_tokens.is_empty() {
+                        self.declared = StackEffect::parse(&comment);
+                    }
+                } else {
+                    comment.push(token.to_string());
+                }
+                continue;
+            }
+            if *token == "(" && !(i > 0 && takes_char(tokens[i - 1].1)) {
+                self.comment = Some(vec![]);
+                continue;
+            }
+            // Remembered so that test failures can say where the test is.
+            if *token == "t{" {
+                self.test_lines.push_back(*line);
+            }
             match self.state {
                 ParserState::Normal => match *token {
                     ":" => {
@@ -68,17 +143,23 @@ impl ForthParser {
                 },
                 ParserState::WordName => {
                     self.word_name = token.to_string();
+                    self.declared = None;
                     self.state = ParserState::WordBody
                 }
                 ParserState::WordBody => {
                     if *token == ";" {
                         self.state = ParserState::Normal;
-                        let expr = self.parse_simple(word_tokens, &mut res)?;
+                        self.locals = Some(vec![]);
+                        let expr = self.parse_simple(word_tokens, &mut res);
+                        self.locals = None;
+                        let expr = expr?;
                         word_tokens = vec![];
-                        res.new_words.insert(
-                            ForthOp::UserWord(self.word_name.clone()),
-                            ForthFunc::User(expr),
-                        );
+                        res.definitions.push(Definition {
+                            name: self.word_name.clone(),
+                            body: expr.into(),
+                            effect: self.declared.take(),
+                            at: res.program.len(),
+                        });
                         self.new_word.clear();
                     } else if *token == ":" {
                         return Err(ForthErr::Msg("Unexpected :".to_string()));
@@ -99,43 +180,85 @@ impl ForthParser {
         res: &mut ParserResult,
     ) -> ForthResult<Vec<ForthExp>> {
         let mut parsed_tokens = vec![];
-        let mut prev_token = "";
         let mut iter = tokens.iter();
         while let Some(token) = iter.next() {
             let parsed_token = match token.as_str() {
                 "variable" => {
                     let var = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
-                    res.variables.insert(var.clone(), 0);
-                    res.new_words
-                        .insert(ForthOp::Variable(var.clone()), ForthFunc::Variable);
                     Ok(ForthExp::Op(ForthOp::Variable(var.clone())))
                 }
+                "2variable" => {
+                    let var = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::TwoVariable(var.clone())))
+                }
                 "constant" => {
-                    let var = iter
-                        .next()
-                        .ok_or(ForthErr::Msg("no name".to_string()))?
-                        .clone();
-                    res.new_words.insert(
-                        ForthOp::UserWord(var.clone()),
-                        ForthFunc::ConstantDef(var.clone()),
-                    );
-                    Ok(ForthExp::Op(ForthOp::UserWord(var.clone())))
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::Constant(name.clone())))
+                }
+                "2constant" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::TwoConstant(name.clone())))
+                }
+                "value" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::Value(name.clone())))
+                }
+                "2value" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::TwoValue(name.clone())))
+                }
+                "'" | "[']" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    match parse_word(name)? {
+                        ForthExp::Op(op) => Ok(ForthExp::Op(ForthOp::Tick(Box::new(op)))),
+                        ForthExp::Number(_) => {
+                            Err(ForthErr::Msg(format!("Not implemented {}", name)))
+                        }
+                    }
+                }
+                "defer" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::Defer(name.clone())))
                 }
-                "@" => {
-                    let var = prev_token;
-                    res.new_words.insert(
-                        ForthOp::GetVar(self.cur),
-                        ForthFunc::GetVar(var.to_string()),
-                    );
-                    Ok(ForthExp::Op(ForthOp::GetVar(self.cur)))
+                "is" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::Is(name.clone())))
                 }
-                "!" => {
-                    let var = prev_token;
-                    res.new_words.insert(
-                        ForthOp::SetVar(self.cur),
-                        ForthFunc::SetVar(var.to_string()),
-                    );
-                    Ok(ForthExp::Op(ForthOp::SetVar(self.cur)))
+                "char" | "[char]" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    let c = name.chars().next().unwrap_or_default();
+                    Ok(ForthExp::Number(c as i64))
+                }
+                "save-system" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::SaveSystem(name.clone())))
+                }
+                "{:" => self.parse_locals(&mut iter, false),
+                "locals|" => self.parse_locals(&mut iter, true),
+                "to" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    match self.local(name) {
+                        Some(n) => Ok(ForthExp::Op(ForthOp::LocalStore(n))),
+                        None => Ok(ForthExp::Op(ForthOp::To(name.clone()))),
+                    }
+                }
+                "+to" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    match self.local(name) {
+                        Some(n) => {
+                            parsed_tokens.push(ForthExp::Op(ForthOp::Local(n)));
+                            parsed_tokens.push(ForthExp::Op(ForthOp::Add));
+                            Ok(ForthExp::Op(ForthOp::LocalStore(n)))
+                        }
+                        None => Ok(ForthExp::Op(ForthOp::PlusTo(name.clone()))),
+                    }
+                }
+                "t{" => Ok(ForthExp::Op(ForthOp::TestStart(
+                    self.test_lines.pop_front().unwrap_or(0),
+                ))),
+                "action-of" => {
+                    let name = iter.next().ok_or(ForthErr::Msg("no name".to_string()))?;
+                    Ok(ForthExp::Op(ForthOp::ActionOf(name.clone())))
                 }
                 "if" => {
                     let expr = self.parse_if(&mut iter, res)?;
@@ -149,37 +272,93 @@ impl ForthParser {
                         None => {
                             res.new_words.insert(
                                 ForthOp::BeginUntil(self.cur),
-                                ForthFunc::BeginUntil(body1),
+                                ForthFunc::BeginUntil(body1.into()),
                             );
                             Ok(ForthExp::Op(ForthOp::BeginUntil(self.cur)))
                         }
                         Some(body) => {
                             res.new_words.insert(
                                 ForthOp::BeginWhile(self.cur),
-                                ForthFunc::BeginWhile(body1, body),
+                                ForthFunc::BeginWhile(body1.into(), body.into()),
                             );
                             Ok(ForthExp::Op(ForthOp::BeginWhile(self.cur)))
                         }
                     }
                 }
-                t => parse_word(t),
+                "s\"" | ".\"" => Err(ForthErr::Msg("Missing \"".to_string())),
+                t => match (string_literal(t), self.local(t)) {
+                    (Some((word, text)), _) => {
+                        res.strings.push(text.to_string());
+                        let literal = ForthExp::Op(ForthOp::SQuote(text.to_string()));
+                        if word == ".\"" {
+                            parsed_tokens.push(literal);
+                            Ok(ForthExp::Op(ForthOp::Type))
+                        } else {
+                            Ok(literal)
+                        }
+                    }
+                    (None, Some(n)) => Ok(ForthExp::Op(ForthOp::Local(n))),
+                    (None, None) => parse_word(t),
+                },
             };
             parsed_tokens.push(parsed_token?);
-            prev_token = token;
             self.cur += 1;
         }
         Ok(parsed_tokens)
     }
 
+    fn local(&self, name: &str) -> Option<usize> {
+        self.locals.as_ref()?.iter().rposition(|l| l == name)
+    }
+
+    // `{: args | uninitialized -- outputs :}` or `LOCALS| args |`, where the
+    // first name of LOCALS| takes the top of the stack. The outputs are only
+    // a comment.
+    fn parse_locals(&mut self, tokens: &mut Iter<String>, legacy: bool) -> ForthResult<ForthExp> {
+        let locals = match &mut self.locals {
+            Some(locals) if locals.is_empty() => locals,
+            Some(_) => return Err(ForthErr::Msg("Locals are already declared".to_string())),
+            None => return Err(ForthErr::Msg("Locals outside of a definition".to_string())),
+        };
+        let end = if legacy { "|" } else { ":}" };
+        let mut args = vec![];
+        let mut uninitialized = vec![];
+        let mut section = 0;
+        let mut closed = false;
+        for token in tokens.by_ref() {
+            match token.as_str() {
+                t if t == end => {
+                    closed = true;
+                    break;
+                }
+                "|" if section == 0 => section = 1,
+                "--" => section = 2,
+                t if section == 0 => args.push(t.to_string()),
+                t if section == 1 => uninitialized.push(t.to_string()),
+                _ => {}
+            }
+        }
+        if !closed {
+            return Err(ForthErr::Msg(format!("Missing {}", end)));
+        }
+        if legacy {
+            args.reverse();
+        }
+        let inputs = args.len();
+        locals.append(&mut args);
+        locals.append(&mut uninitialized);
+        Ok(ForthExp::Op(ForthOp::Locals(inputs, locals.len())))
+    }
+
     fn parse_if(
         &mut self,
         tokens: &mut Iter<String>,
         res: &mut ParserResult,
-    ) -> ForthResult<(Vec<ForthExp>, Option<Vec<ForthExp>>)> {
+    ) -> ForthResult<(Body, Option<Body>)> {
         let mut then: Vec<String> = vec![];
         let mut r#else: Vec<String> = vec![];
         let mut else_found = false;
-        while let Some(token) = tokens.next() {
+        for token in tokens.by_ref() {
             match token.as_str() {
                 "then" => break,
                 "else" => else_found = true,
@@ -195,9 +374,9 @@ impl ForthParser {
         let then_parsed = self.parse_simple(then, res)?;
         if else_found {
             let else_parsed = self.parse_simple(r#else, res)?;
-            Ok((then_parsed, Some(else_parsed)))
+            Ok((then_parsed.into(), Some(else_parsed.into())))
         } else {
-            Ok((then_parsed, None))
+            Ok((then_parsed.into(), None))
         }
     }
 
@@ -209,7 +388,7 @@ impl ForthParser {
         let mut body1 = vec![];
         let mut body2 = vec![];
         let mut while_found = false;
-        while let Some(token) = tokens.next() {
+        for token in tokens.by_ref() {
             match token.as_str() {
                 "until" => {
                     if while_found {
@@ -245,68 +424,224 @@ impl ForthParser {
     }
 }
 
-fn tokenize(expr: &str) -> Vec<&str> {
-    expr.split_whitespace().collect()
+// Splits into words numbered by line, dropping `\` comments that run to the
+// end of the line. `S" text"` and `." text"` outside `( )` comments stay one
+// token, from the word to just before the closing quote.
+fn tokenize(expr: &str) -> Vec<(usize, &str)> {
+    let mut tokens = vec![];
+    let mut comment = false;
+    for (n, line) in expr.lines().enumerate() {
+        let mut prev = "";
+        let mut rest = line.trim_start();
+        while !rest.is_empty() {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let mut token = &rest[..end];
+            let takes_char = takes_char(&prev.to_ascii_lowercase());
+            if token == "\\" && !takes_char {
+                break;
+            }
+            if comment {
+                comment = !token.ends_with(')');
+            } else {
+                comment = token == "(" && !takes_char;
+            }
+            let text = rest[end..].chars().next().map(|c| end + c.len_utf8());
+            match text.and_then(|start| Some(start + rest[start..].find('"')?)) {
+                Some(close) if takes_string(token) && !takes_char && !comment => {
+                    token = &rest[..close];
+                    rest = &rest[close + 1..];
+                }
+                _ => rest = &rest[end..],
+            }
+            tokens.push((n + 1, token));
+            prev = token;
+            rest = rest.trim_start();
+        }
+    }
+    tokens
+}
+
+// CHAR and [CHAR] read the next word even if it is `(` or `\`.
+fn takes_char(word: &str) -> bool {
+    word == "char" || word == "[char]"
+}
+
+fn takes_string(word: &str) -> bool {
+    word.eq_ignore_ascii_case("s\"") || word == ".\""
+}
+
+// The word and text of a string literal token.
+fn string_literal(token: &str) -> Option<(&str, &str)> {
+    let word = token.get(..2)?;
+    let mut text = token[2..].chars();
+    match text.next() {
+        Some(c) if c.is_whitespace() && takes_string(word) => Some((word, text.as_str())),
+        _ => None,
+    }
+}
+
+// The code point of a `'c'` literal.
+fn char_literal(token: &str) -> Option<i64> {
+    let mut chars = token.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c as i64),
+        _ => None,
+    }
+}
+
+// Built-in words and the ops they compile to.
+const WORDS: &[(&str, ForthOp)] = &[
+    ("+", ForthOp::Add),
+    ("-", ForthOp::Sub),
+    ("*", ForthOp::Mul),
+    ("/", ForthOp::Div),
+    ("1+", ForthOp::Add1),
+    ("1-", ForthOp::Sub1),
+    ("2+", ForthOp::Add2),
+    ("2-", ForthOp::Sub2),
+    ("2*", ForthOp::Mul2),
+    ("2/", ForthOp::Div2),
+    ("mod", ForthOp::Mod),
+    ("/mod", ForthOp::DivMod),
+    ("*/", ForthOp::MulDiv),
+    ("*/mod", ForthOp::MulDivMod),
+    ("abs", ForthOp::Abs),
+    ("negate", ForthOp::Neg),
+    ("dup", ForthOp::Dup),
+    ("drop", ForthOp::Drop),
+    ("over", ForthOp::Over),
+    ("rot", ForthOp::Rot),
+    ("swap", ForthOp::Swap),
+    ("pick", ForthOp::Pick),
+    ("roll", ForthOp::Roll),
+    (".", ForthOp::Print),
+    (".s", ForthOp::PrintStack),
+    ("?", ForthOp::PrintAt),
+    ("dump", ForthOp::Dump),
+    ("emit", ForthOp::Emit),
+    ("type", ForthOp::Type),
+    ("key", ForthOp::Key),
+    ("accept", ForthOp::Accept),
+    ("and", ForthOp::And),
+    ("or", ForthOp::Or),
+    ("xor", ForthOp::Xor),
+    ("not", ForthOp::Not),
+    ("<", ForthOp::Lt),
+    ("=", ForthOp::Eq),
+    (">", ForthOp::Gt),
+    ("<=", ForthOp::Le),
+    (">=", ForthOp::Ge),
+    ("<>", ForthOp::Ne),
+    ("0<", ForthOp::Lt0),
+    ("0=", ForthOp::Eq0),
+    ("0>", ForthOp::Gt0),
+    ("0<>", ForthOp::Ne0),
+    ("invert", ForthOp::Invert),
+    ("true", ForthOp::True),
+    ("false", ForthOp::False),
+    ("?dup", ForthOp::IfDup),
+    ("2@", ForthOp::TwoFetch),
+    ("2!", ForthOp::TwoStore),
+    ("2dup", ForthOp::Dup2),
+    ("2drop", ForthOp::Drop2),
+    ("2swap", ForthOp::Swap2),
+    ("2over", ForthOp::Over2),
+    ("depth", ForthOp::Depth),
+    ("lshift", ForthOp::Lshift),
+    ("rshift", ForthOp::Rshift),
+    ("max", ForthOp::Max),
+    ("min", ForthOp::Min),
+    ("@", ForthOp::Fetch),
+    ("!", ForthOp::Store),
+    ("+!", ForthOp::AddStore),
+    ("c@", ForthOp::CFetch),
+    ("c!", ForthOp::CStore),
+    ("here", ForthOp::Here),
+    ("allot", ForthOp::Allot),
+    (",", ForthOp::Comma),
+    ("c,", ForthOp::CComma),
+    ("cells", ForthOp::Cells),
+    ("cell+", ForthOp::CellAdd),
+    ("chars", ForthOp::Chars),
+    ("char+", ForthOp::CharAdd),
+    ("compare", ForthOp::Compare),
+    ("search", ForthOp::Search),
+    ("/string", ForthOp::SlashString),
+    ("-trailing", ForthOp::DashTrailing),
+    ("blank", ForthOp::Blank),
+    ("move", ForthOp::Move),
+    ("cmove", ForthOp::CMove),
+    ("cmove>", ForthOp::CMoveUp),
+    ("fill", ForthOp::Fill),
+    ("erase", ForthOp::Erase),
+    ("s+", ForthOp::SPlus),
+    ("r/o", ForthOp::ReadOnly),
+    ("w/o", ForthOp::WriteOnly),
+    ("r/w", ForthOp::ReadWrite),
+    ("bin", ForthOp::Bin),
+    ("create-file", ForthOp::CreateFile),
+    ("open-file", ForthOp::OpenFile),
+    ("close-file", ForthOp::CloseFile),
+    ("read-file", ForthOp::ReadFile),
+    ("read-line", ForthOp::ReadLine),
+    ("write-file", ForthOp::WriteFile),
+    ("write-line", ForthOp::WriteLine),
+    ("file-size", ForthOp::FileSize),
+    ("file-position", ForthOp::FilePosition),
+    ("reposition-file", ForthOp::RepositionFile),
+    ("delete-file", ForthOp::DeleteFile),
+    ("rename-file", ForthOp::RenameFile),
+    ("allocate", ForthOp::Allocate),
+    ("free", ForthOp::Free),
+    ("resize", ForthOp::Resize),
+    ("environment?", ForthOp::EnvironmentQuery),
+    ("execute", ForthOp::Execute),
+    ("defer@", ForthOp::DeferFetch),
+    ("defer!", ForthOp::DeferStore),
+    ("->", ForthOp::TestArrow),
+    ("}t", ForthOp::TestEnd),
+];
+
+// WORDS indexed both ways, built on first use. Where several words compile to
+// the same op the first one names it.
+fn ops() -> &'static HashMap<&'static str, ForthOp> {
+    static OPS: OnceLock<HashMap<&str, ForthOp>> = OnceLock::new();
+    OPS.get_or_init(|| {
+        let mut ops = HashMap::new();
+        for (word, op) in WORDS {
+            ops.entry(*word).or_insert_with(|| op.clone());
+        }
+        ops
+    })
+}
+
+fn names() -> &'static HashMap<ForthOp, &'static str> {
+    static NAMES: OnceLock<HashMap<ForthOp, &str>> = OnceLock::new();
+    NAMES.get_or_init(|| {
+        let mut names = HashMap::new();
+        for (word, op) in WORDS {
+            names.entry(op.clone()).or_insert(*word);
+        }
+        names
+    })
+}
+
+// The word that compiles to a built-in op, as traces and profiles show it.
+pub(crate) fn word_of(op: &ForthOp) -> Option<&'static str> {
+    names().get(op).copied()
 }
 
 fn parse_word(token: &str) -> ForthResult<ForthExp> {
+    if let Some(c) = char_literal(token) {
+        return Ok(ForthExp::Number(c));
+    }
     let potential_int: Result<i64, ParseIntError> = token.parse();
     let res = match potential_int {
         Ok(v) => ForthExp::Number(v),
         Err(_) => {
-            let op = match token {
-                "+" => ForthOp::Add,
-                "-" => ForthOp::Sub,
-                "*" => ForthOp::Mul,
-                "/" => ForthOp::Div,
-                "1+" => ForthOp::Add1,
-                "1-" => ForthOp::Sub1,
-                "2+" => ForthOp::Add2,
-                "2-" => ForthOp::Sub2,
-                "2*" => ForthOp::Mul2,
-                "2/" => ForthOp::Div2,
-                "mod" => ForthOp::Mod,
-                "/mod" => ForthOp::DivMod,
-                "*/" => ForthOp::FMD,
-                "*/mod" => ForthOp::FMDM,
-                "abs" => ForthOp::Abs,
-                "negate" => ForthOp::Neg,
-                "dup" => ForthOp::Dup,
-                "drop" => ForthOp::Drop,
-                "over" => ForthOp::Over,
-                "rot" => ForthOp::Rot,
-                "swap" => ForthOp::Swap,
-                "pick" => ForthOp::Pick,
-                "roll" => ForthOp::Roll,
-                "." => ForthOp::Print,
-                "and" => ForthOp::And,
-                "or" => ForthOp::Or,
-                "xor" => ForthOp::Xor,
-                "not" => ForthOp::Not,
-                "<" => ForthOp::Lt,
-                "=" => ForthOp::Eq,
-                ">" => ForthOp::Gt,
-                "<=" => ForthOp::Le,
-                ">=" => ForthOp::Ge,
-                "<>" => ForthOp::Ne,
-                "0<" => ForthOp::Lt0,
-                "0=" => ForthOp::Eq0,
-                "0>" => ForthOp::Gt0,
-                "0<>" => ForthOp::Ne0,
-                "invert" => ForthOp::Invert,
-                "true" => ForthOp::True,
-                "false" => ForthOp::False,
-                "?dup" => ForthOp::IfDup,
-                "2dup" => ForthOp::Dup2,
-                "2drop" => ForthOp::Drop2,
-                "2swap" => ForthOp::Swap2,
-                "2over" => ForthOp::Over2,
-                "depth" => ForthOp::Depth,
-                "lshift" => ForthOp::Lshift,
-                "rshift" => ForthOp::Rshift,
-                "max" => ForthOp::Max,
-                "min" => ForthOp::Min,
-                word => ForthOp::UserWord(word.to_string()),
+            let op = match ops().get(token) {
+                Some(op) => op.clone(),
+                None => ForthOp::UserWord(token.to_string()),
             };
             ForthExp::Op(op)
         }
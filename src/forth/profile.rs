@@ -0,0 +1,124 @@
+use super::debug::{TraceStep, Tracer};
+use super::interp::ForthInterp;
+use super::types::*;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ProfileEntry {
+    pub word: String,
+    pub calls: u64,
+    pub inclusive: Duration,
+    pub exclusive: Duration,
+}
+
+struct Frame {
+    word: String,
+    start: Instant,
+    children: Duration,
+}
+
+// Tracer that counts executions and time spent per word and per call stack.
+#[derive(Default)]
+pub struct Profiler {
+    entries: HashMap<String, ProfileEntry>,
+    folded: HashMap<String, Duration>,
+    frames: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+        self.folded.clear();
+        self.frames.clear();
+    }
+
+    // Entries sorted by exclusive time, the most expensive first.
+    pub fn entries(&self) -> Vec<ProfileEntry> {
+        let mut res: Vec<ProfileEntry> = self.entries.values().cloned().collect();
+        res.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.word.cmp(&b.word)));
+        res
+    }
+
+    pub fn report(&self) -> String {
+        let mut res = format!(
+            "{:<20} {:>10} {:>14} {:>14}\n",
+            "word", "calls", "incl (us)", "excl (us)"
+        );
+        for entry in self.entries() {
+            res.push_str(&format!(
+                "{:<20} {:>10} {:>14} {:>14}\n",
+                entry.word,
+                entry.calls,
+                entry.inclusive.as_micros(),
+                entry.exclusive.as_micros()
+            ));
+        }
+        res
+    }
+
+    // Writes `outer;inner nanoseconds` lines, the input format of flamegraph tools.
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &Duration)> = self.folded.iter().collect();
+        stacks.sort();
+        for (stack, time) in stacks {
+            writeln!(out, "{} {}", stack, time.as_nanos())?;
+        }
+        Ok(())
+    }
+}
+
+fn profile_name(step: &TraceStep) -> String {
+    if step.word.parse::<i64>().is_ok() {
+        "(literal)".to_string()
+    } else {
+        step.word.replace(';', ":")
+    }
+}
+
+impl Tracer for Profiler {
+    fn before(&mut self, _interp: &ForthInterp, step: &TraceStep) -> ForthResult<()> {
+        self.frames.push(Frame {
+            word: profile_name(step),
+            start: Instant::now(),
+            children: Duration::default(),
+        });
+        Ok(())
+    }
+
+    fn after(&mut self, _interp: &ForthInterp, _step: &TraceStep) -> ForthResult<()> {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let elapsed = frame.start.elapsed();
+        let exclusive = elapsed.saturating_sub(frame.children);
+        if let Some(parent) = self.frames.last_mut() {
+            parent.children += elapsed;
+        }
+        let recursive = self.frames.iter().any(|f| f.word == frame.word);
+        let mut stack: Vec<&str> = self.frames.iter().map(|f| f.word.as_str()).collect();
+        stack.push(&frame.word);
+        *self.folded.entry(stack.join(";")).or_default() += exclusive;
+
+        let entry = self
+            .entries
+            .entry(frame.word.clone())
+            .or_insert_with(|| ProfileEntry {
+                word: frame.word,
+                ..ProfileEntry::default()
+            });
+        entry.calls += 1;
+        entry.exclusive += exclusive;
+        // Time of a recursive call is already part of the outermost one.
+        if !recursive {
+            entry.inclusive += elapsed;
+        }
+        Ok(())
+    }
+}
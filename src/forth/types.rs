@@ -1,6 +1,15 @@
 use super::interp::ForthInterp;
 use std::fmt;
 use std::fmt::Debug;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type HostFn = Rc<dyn Fn(&mut ForthInterp) -> ForthResult<()>>;
+
+// Compiled code is immutable once parsed and shared by every execution.
+pub type Body = Rc<[ForthExp]>;
 
 #[derive(Clone)]
 pub enum ForthExp {
@@ -11,22 +20,218 @@ pub enum ForthExp {
 #[derive(Clone)]
 pub enum ForthFunc {
     Native(fn(&mut ForthInterp) -> Result<(), ForthErr>),
-    User(Vec<ForthExp>),
-    Variable,
-    ConstantDef(String),
-    GetVar(String),
-    SetVar(String),
-    IfThenElse((Vec<ForthExp>, Option<Vec<ForthExp>>)),
-    BeginUntil(Vec<ForthExp>),
-    BeginWhile(Vec<ForthExp>, Vec<ForthExp>),
+    Host(HostFn),
+    User(Body),
+    Constant(i64),
+    TwoConstant(i64, i64),
+    // Defining words with the number of cells of what they define.
+    Variable(String, usize),
+    ConstantDef(String, usize),
+    ValueDef(String, usize),
+    // The cells of a value at an address, and TO and +TO for it by name.
+    Value(i64, usize),
+    To(String),
+    PlusTo(String),
+    Tick(ForthOp),
+    Defer(String),
+    Is(String),
+    ActionOf(String),
+    IfThenElse((Body, Option<Body>)),
+    BeginUntil(Body),
+    BeginWhile(Body, Body),
+    SaveSystem(String),
+    TestStart(usize),
+    Locals(usize, usize),
+    Local(usize),
+    LocalStore(usize),
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ForthErr {
     Msg(String),
+    LimitExceeded(Limit),
+    Forbidden(String, Capability),
+    Interrupted,
+}
+
+// Thread-safe switch that stops a running evaluation at the next instruction.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Checked on every step, so the common case is a plain load.
+    pub(crate) fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::SeqCst)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Capability {
+    FileAccess,
+    ConsoleInput,
+    Environment,
+    HostNatives,
+}
+
+// Word sets an embedding application allows untrusted code to compile.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Capabilities {
+    pub file_access: bool,
+    pub console_input: bool,
+    pub environment: bool,
+    pub host_natives: bool,
+}
+
+impl Capabilities {
+    pub fn all() -> Capabilities {
+        Capabilities {
+            file_access: true,
+            console_input: true,
+            environment: true,
+            host_natives: true,
+        }
+    }
+
+    pub fn none() -> Capabilities {
+        Capabilities {
+            file_access: false,
+            console_input: false,
+            environment: false,
+            host_natives: false,
+        }
+    }
+
+    pub fn allows(&self, cap: Capability) -> bool {
+        match cap {
+            Capability::FileAccess => self.file_access,
+            Capability::ConsoleInput => self.console_input,
+            Capability::Environment => self.environment,
+            Capability::HostNatives => self.host_natives,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::all()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Limit {
+    Steps,
+    StackDepth,
+    ReturnDepth,
+    Words,
+    DataSpace,
+    Timeout,
+}
+
+// Resource limits for a single top-level evaluation; `None` means unlimited.
+// Only the return depth is limited by default.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_stack_depth: Option<usize>,
+    pub max_return_depth: Option<usize>,
+    pub max_words: Option<usize>,
+    pub max_data_space: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+// Far deeper than any real program recurses, but a runaway recursion stops
+// before its frames take up much memory.
+const RETURN_DEPTH: usize = 100_000;
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: None,
+            max_stack_depth: None,
+            max_return_depth: Some(RETURN_DEPTH),
+            max_words: None,
+            max_data_space: None,
+            timeout: None,
+        }
+    }
+}
+
+// A `T{ ... -> ... }T` test whose results differ from the expected values.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TestFailure {
+    pub line: usize,
+    pub expected: Vec<i64>,
+    pub actual: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TestResults {
+    pub passed: usize,
+    pub failures: Vec<TestFailure>,
 }
 
 pub type ForthResult<T> = ::std::result::Result<T, ForthErr>;
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CellWidth {
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+impl CellWidth {
+    pub fn from_bits(bits: u32) -> Option<CellWidth> {
+        match bits {
+            16 => Some(CellWidth::Bits16),
+            32 => Some(CellWidth::Bits32),
+            64 => Some(CellWidth::Bits64),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::Bits16 => 16,
+            CellWidth::Bits32 => 32,
+            CellWidth::Bits64 => 64,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        (self.bits() / 8) as usize
+    }
+
+    // Truncates a value to the cell width, sign-extending it back to i64.
+    pub fn wrap(self, n: i64) -> i64 {
+        match self {
+            CellWidth::Bits16 => n as i16 as i64,
+            CellWidth::Bits32 => n as i32 as i64,
+            CellWidth::Bits64 => n,
+        }
+    }
+
+    // Reinterprets a cell as an unsigned number of the cell width.
+    pub fn unsigned(self, n: i64) -> u64 {
+        match self {
+            CellWidth::Bits16 => n as u16 as u64,
+            CellWidth::Bits32 => n as u32 as u64,
+            CellWidth::Bits64 => n as u64,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub enum ForthOp {
     Add,
@@ -35,8 +240,8 @@ pub enum ForthOp {
     Div,
     Mod,
     DivMod,
-    FMD,
-    FMDM,
+    MulDiv,
+    MulDivMod,
     Abs,
     Neg,
     Add1,
@@ -53,6 +258,13 @@ pub enum ForthOp {
     Pick,
     Roll,
     Print,
+    PrintStack,
+    PrintAt,
+    Dump,
+    Emit,
+    Type,
+    Key,
+    Accept,
     And,
     Or,
     Xor,
@@ -80,9 +292,78 @@ pub enum ForthOp {
     Rshift,
     Max,
     Min,
+    Fetch,
+    Store,
+    TwoFetch,
+    TwoStore,
+    AddStore,
+    CFetch,
+    CStore,
+    Here,
+    Allot,
+    Comma,
+    CComma,
+    Cells,
+    CellAdd,
+    Chars,
+    CharAdd,
+    Compare,
+    Search,
+    SlashString,
+    DashTrailing,
+    Blank,
+    Move,
+    CMove,
+    CMoveUp,
+    Fill,
+    Erase,
+    SPlus,
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+    Bin,
+    CreateFile,
+    OpenFile,
+    CloseFile,
+    ReadFile,
+    ReadLine,
+    WriteFile,
+    WriteLine,
+    FileSize,
+    FilePosition,
+    RepositionFile,
+    DeleteFile,
+    RenameFile,
+    Allocate,
+    Free,
+    Resize,
+    EnvironmentQuery,
+    Execute,
+    DeferFetch,
+    DeferStore,
+    TestArrow,
+    TestEnd,
+    TestStart(usize),
+    // Locals are numbered within their definition. Locals(args, total)
+    // starts a frame of `total` cells, the first `args` taken from the stack.
+    Locals(usize, usize),
+    Local(usize),
+    LocalStore(usize),
     Variable(String),
-    GetVar(usize),
-    SetVar(usize),
+    TwoVariable(String),
+    Constant(String),
+    TwoConstant(String),
+    Value(String),
+    TwoValue(String),
+    To(String),
+    PlusTo(String),
+    Tick(Box<ForthOp>),
+    Defer(String),
+    Is(String),
+    ActionOf(String),
+    SaveSystem(String),
+    // An `S" text"` literal, placed in data space when its line is compiled.
+    SQuote(String),
     UserWord(String),
     IfThenElse(usize),
     BeginUntil(usize),
@@ -110,6 +391,55 @@ impl fmt::Display for ForthErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ForthErr::Msg(str) => write!(f, "{}", str),
+            ForthErr::LimitExceeded(limit) => write!(f, "{}", limit),
+            ForthErr::Forbidden(word, cap) => {
+                write!(f, "{} is not allowed: {} is disabled", word, cap)
+            }
+            ForthErr::Interrupted => write!(f, "Interrupted"),
         }
     }
 }
+
+impl std::error::Error for ForthErr {}
+
+impl fmt::Display for TestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stack = |nums: &[i64]| {
+            let nums: Vec<String> = nums.iter().map(|n| n.to_string()).collect();
+            format!("[ {} ]", nums.join(" "))
+        };
+        write!(
+            f,
+            "line {}: expected {} but got {}",
+            self.line,
+            stack(&self.expected),
+            stack(&self.actual)
+        )
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self {
+            Capability::FileAccess => "file access",
+            Capability::ConsoleInput => "console input",
+            Capability::Environment => "environment access",
+            Capability::HostNatives => "host natives",
+        };
+        write!(f, "{}", what)
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self {
+            Limit::Steps => "Step limit",
+            Limit::StackDepth => "Stack depth limit",
+            Limit::ReturnDepth => "Return stack depth limit",
+            Limit::Words => "Dictionary size limit",
+            Limit::DataSpace => "Data space limit",
+            Limit::Timeout => "Time limit",
+        };
+        write!(f, "{} exceeded", what)
+    }
+}
@@ -0,0 +1,7 @@
+mod forth;
+
+pub use crate::forth::{
+    CancelHandle, Capabilities, Capability, CellWidth, Debugger, ForthErr, ForthInterp,
+    ForthResult, Limit, Limits, ProfileEntry, Profiler, StackEffect, StackTracer, TestFailure,
+    TestResults, TraceStep, Tracer,
+};
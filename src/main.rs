@@ -1,33 +1,437 @@
-use forth::ForthInterp;
+use rfort::{
+    Capabilities, CellWidth, Debugger, ForthErr, ForthInterp, Limits, Profiler, StackTracer,
+};
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
 use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::time::Duration;
 
-mod forth;
+// Ctrl-C at the prompt stops the running line instead of killing the process,
+// and quits while the prompt waits for input.
+#[cfg(unix)]
+mod interrupt {
+    use rfort::CancelHandle;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::OnceLock;
 
-fn slurp_expr() -> String {
+    const SIGINT: i32 = 2;
+
+    static HANDLE: OnceLock<CancelHandle> = OnceLock::new();
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    extern "C" fn on_sigint(_: i32) {
+        if !RUNNING.load(Ordering::SeqCst) {
+            unsafe { _exit(128 + SIGINT) }
+        }
+        if let Some(handle) = HANDLE.get() {
+            handle.cancel();
+        }
+    }
+
+    pub fn running(on: bool) {
+        RUNNING.store(on, Ordering::SeqCst);
+    }
+
+    pub fn install(handle: CancelHandle) {
+        if HANDLE.set(handle).is_ok() {
+            unsafe {
+                signal(SIGINT, on_sigint);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod interrupt {
+    pub fn install(_: rfort::CancelHandle) {}
+    pub fn running(_: bool) {}
+}
+
+// What the prompt prints after a line ran without errors.
+#[derive(Clone, Copy)]
+enum Status {
+    Ok,
+    Stack,
+    Silent,
+}
+
+impl Status {
+    fn parse(name: &str) -> Option<Status> {
+        match name {
+            "ok" => Some(Status::Ok),
+            "stack" => Some(Status::Stack),
+            "silent" => Some(Status::Silent),
+            _ => None,
+        }
+    }
+}
+
+struct Args {
+    cell_width: CellWidth,
+    atomic: bool,
+    strict_effects: bool,
+    legacy_invert: bool,
+    leak_report: bool,
+    limits: Limits,
+    capabilities: Capabilities,
+    profile: Option<String>,
+    image: Option<String>,
+    status: Status,
+    test: bool,
+    files: Vec<String>,
+}
+
+fn slurp_expr() -> Option<String> {
     let mut expr = String::new();
 
-    io::stdin()
+    let read = io::stdin()
         .read_line(&mut expr)
         .expect("Failed to read line");
 
-    expr
+    if read == 0 {
+        None
+    } else {
+        Some(expr)
+    }
+}
+
+fn num_arg(flag: &str, val: Option<String>) -> Result<u64, ForthErr> {
+    val.and_then(|v| v.parse().ok())
+        .ok_or(ForthErr::Msg(format!("{} expects a number", flag)))
 }
 
-fn main() {
-    let mut interp = ForthInterp::new();
+fn parse_args() -> Result<Args, ForthErr> {
+    let mut args = env::args().skip(1).peekable();
+    let mut res = Args {
+        cell_width: CellWidth::Bits64,
+        atomic: false,
+        strict_effects: false,
+        legacy_invert: false,
+        leak_report: false,
+        limits: Limits::default(),
+        capabilities: Capabilities::all(),
+        profile: None,
+        image: None,
+        status: Status::Stack,
+        test: false,
+        files: vec![],
+    };
+    if args.peek().map(|a| a.as_str()) == Some("test") {
+        res.test = true;
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cell-bits" => {
+                res.cell_width = args
+                    .next()
+                    .and_then(|b| b.parse().ok())
+                    .and_then(CellWidth::from_bits)
+                    .ok_or(ForthErr::Msg(
+                        "--cell-bits expects 16, 32 or 64".to_string(),
+                    ))?;
+            }
+            "--atomic" => res.atomic = true,
+            "--strict-effects" => res.strict_effects = true,
+            "--legacy-invert" => res.legacy_invert = true,
+            "--leak-report" => res.leak_report = true,
+            "--status" => {
+                res.status = args
+                    .next()
+                    .and_then(|s| Status::parse(&s))
+                    .ok_or(ForthErr::Msg(
+                        "--status expects ok, stack or silent".to_string(),
+                    ))?;
+            }
+            "--sandbox" => res.capabilities = Capabilities::none(),
+            "--max-steps" => res.limits.max_steps = Some(num_arg(&arg, args.next())?),
+            "--max-depth" => {
+                let depth = num_arg(&arg, args.next())?;
+                res.limits.max_stack_depth = Some(depth as usize);
+                res.limits.max_return_depth = Some(depth as usize);
+            }
+            "--timeout-ms" => {
+                res.limits.timeout = Some(Duration::from_millis(num_arg(&arg, args.next())?))
+            }
+            "--image" => {
+                res.image = Some(
+                    args.next()
+                        .ok_or(ForthErr::Msg("--image expects a file name".to_string()))?,
+                )
+            }
+            "--profile" => {
+                res.profile = Some(
+                    args.next()
+                        .ok_or(ForthErr::Msg("--profile expects a file name".to_string()))?,
+                )
+            }
+            a if a.starts_with("--") => {
+                return Err(ForthErr::Msg(format!("Unknown argument {}", a)))
+            }
+            file => res.files.push(file.to_string()),
+        }
+    }
+    Ok(res)
+}
+
+const REPL_HELP: &str = "\
+#trace on|off     log every executed word with the stack around it
+#break WORD       pause when WORD is about to run
+#unbreak WORD     remove a breakpoint
+#debug CODE       run CODE one instruction at a time
+#profile on|off   record calls and time spent in every word
+#profile report   show the recorded profile
+#profile reset    forget the recorded profile
+#profile save F   write the profile to F as folded stacks for flamegraph
+#status ok        print ok after every line
+#status stack     print the stack after every line
+#status silent    print nothing but errors and warnings";
+
+fn save_profile(profiler: &Profiler, path: &str) -> Result<(), ForthErr> {
+    File::create(path)
+        .and_then(|mut file| profiler.write_folded(&mut file))
+        .map_err(|e| ForthErr::Msg(format!("Can't write {}: {}", path, e)))
+}
+
+struct Session {
+    debugger: Rc<RefCell<Debugger>>,
+    profiler: Rc<RefCell<Profiler>>,
+    profiling: bool,
+    status: Status,
+}
+
+impl Session {
+    // Handles a `#` command, returning Forth code that still has to be run.
+    fn command(&mut self, cmd: &str) -> Result<Option<String>, ForthErr> {
+        let mut args = cmd.splitn(2, char::is_whitespace);
+        let name = args.next().unwrap_or("");
+        let arg = args.next().unwrap_or("").trim();
+        let mut debugger = self.debugger.borrow_mut();
+        match (name, arg) {
+            ("trace", "on") => debugger.set_trace(Some(StackTracer::stderr())),
+            ("trace", "off") => debugger.set_trace(None),
+            ("break", word) if !word.is_empty() => debugger.add_breakpoint(word),
+            ("unbreak", word) if !word.is_empty() => {
+                if !debugger.remove_breakpoint(word) {
+                    return Err(ForthErr::Msg(format!("No breakpoint on {}", word)));
+                }
+            }
+            ("debug", code) => {
+                debugger.step();
+                return Ok(Some(code.to_string()));
+            }
+            ("profile", "on") => self.profiling = true,
+            ("profile", "off") => self.profiling = false,
+            ("profile", "report") => print!("{}", self.profiler.borrow().report()),
+            ("profile", "reset") => self.profiler.borrow_mut().reset(),
+            ("profile", save) if save.starts_with("save ") => {
+                save_profile(&self.profiler.borrow(), save[5..].trim())?
+            }
+            ("status", status) => {
+                self.status = Status::parse(status).ok_or(ForthErr::Msg(
+                    "#status expects ok, stack or silent".to_string(),
+                ))?
+            }
+            ("help", _) => println!("{}", REPL_HELP),
+            _ => {
+                return Err(ForthErr::Msg(format!(
+                    "Unknown command #{}, try #help",
+                    cmd
+                )))
+            }
+        }
+        Ok(None)
+    }
+
+    // The debugger takes over while it has something to do, timings would be
+    // meaningless with it pausing anyway.
+    fn install(&self, interp: &mut ForthInterp) {
+        if self.debugger.borrow().is_active() {
+            interp.set_tracer(Some(self.debugger.clone()));
+        } else if self.profiling {
+            interp.set_tracer(Some(self.profiler.clone()));
+        } else {
+            interp.set_tracer(None);
+        }
+    }
+}
+
+fn repl(interp: &mut ForthInterp, status: Status) {
+    interrupt::install(interp.cancel_handle());
+    let mut session = Session {
+        debugger: Rc::new(RefCell::new(Debugger::stdio())),
+        profiler: Rc::new(RefCell::new(Profiler::new())),
+        profiling: false,
+        status,
+    };
     loop {
         println!("rforth >");
-        let expr = slurp_expr();
-        match interp.eval_str(&expr) {
-            Ok(_) => {
-                print!("// stack => ");
-                for exp in &interp.stack {
-                    print!("{} ", exp)
+        let mut expr = match slurp_expr() {
+            Some(expr) => expr,
+            None => break,
+        };
+        if let Some(cmd) = expr.trim().strip_prefix('#') {
+            match session.command(cmd) {
+                Ok(Some(code)) => expr = code,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("// err => {}", e);
+                    continue;
                 }
-                println!();
             }
+        }
+        session.install(interp);
+        interrupt::running(true);
+        let res = interp.eval_str(&expr);
+        interrupt::running(false);
+        session.debugger.borrow_mut().resume();
+        for warning in interp.take_warnings() {
+            println!("// warning => {}", warning);
+        }
+        for failure in interp.take_test_results().failures {
+            println!("// test failed => {}", failure);
+        }
+        match (res, session.status) {
+            (Ok(_), Status::Ok) => println!(" ok"),
+            (Ok(_), Status::Stack) => println!("// stack => {}", interp.format_stack()),
+            (Ok(_), Status::Silent) => {}
+            (Err(e), _) => println!("// err => {}", e),
+        }
+    }
+}
+
+fn new_interp(args: &Args) -> Result<ForthInterp, ForthErr> {
+    let mut interp = ForthInterp::with_cell_width(args.cell_width);
+    interp.set_transactional(args.atomic);
+    interp.set_strict_effects(args.strict_effects);
+    interp.set_legacy_invert(args.legacy_invert);
+    interp.set_leak_report(args.leak_report);
+    interp.set_limits(args.limits.clone());
+    interp.set_capabilities(args.capabilities);
+    if let Some(image) = &args.image {
+        interp
+            .load_image(image)
+            .map_err(|e| ForthErr::Msg(format!("{}: {}", image, e)))?;
+    }
+    Ok(interp)
+}
+
+const TEST_EXTENSIONS: &[&str] = &["fs", "fth", "4th", "f", "forth"];
+
+// Expands directories into the Forth sources they contain, sorted by name.
+fn test_files(paths: &[String]) -> Result<Vec<PathBuf>, ForthErr> {
+    let mut files = vec![];
+    for path in paths {
+        let path = Path::new(path);
+        if !path.is_dir() {
+            files.push(path.to_path_buf());
+            continue;
+        }
+        let entries =
+            fs::read_dir(path).map_err(|e| ForthErr::Msg(format!("{}: {}", path.display(), e)))?;
+        let mut found: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| TEST_EXTENSIONS.contains(&ext))
+            })
+            .collect();
+        found.sort();
+        files.append(&mut found);
+    }
+    Ok(files)
+}
+
+// Runs every test file in a fresh interpreter; fails if any test fails.
+fn run_tests(args: &Args) -> Result<bool, ForthErr> {
+    let files = test_files(&args.files)?;
+    let (mut passed, mut failed) = (0, 0);
+    for file in &files {
+        let mut interp = new_interp(args)?;
+        let res = interp.eval_file(file);
+        let results = interp.take_test_results();
+        passed += results.passed;
+        failed += results.failures.len();
+        for failure in &results.failures {
+            println!("{}: {}", file.display(), failure);
+        }
+        if let Err(e) = res {
+            println!("{}: {}", file.display(), e);
+            failed += 1;
+        }
+    }
+    println!(
+        "{} passed, {} failed in {} files",
+        passed,
+        failed,
+        files.len()
+    );
+    Ok(failed == 0)
+}
 
-            Err(e) => println!("// err => {}", e),
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if args.test {
+        match run_tests(&args) {
+            Ok(true) => process::exit(0),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2);
+            }
+        }
+    }
+    let mut interp = match new_interp(&args) {
+        Ok(interp) => interp,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if args.files.is_empty() {
+        repl(&mut interp, args.status);
+        return;
+    }
+    let profiler = Rc::new(RefCell::new(Profiler::new()));
+    if args.profile.is_some() {
+        interp.set_tracer(Some(profiler.clone()));
+    }
+    let mut status = 0;
+    for file in &args.files {
+        let res = interp.eval_file(file);
+        for warning in interp.take_warnings() {
+            eprintln!("{}: warning: {}", file, warning);
+        }
+        if let Err(e) = res {
+            eprintln!("{}: {}", file, e);
+            status = 1;
+            break;
+        }
+    }
+    if let Some(path) = &args.profile {
+        eprint!("{}", profiler.borrow().report());
+        if let Err(e) = save_profile(&profiler.borrow(), path) {
+            eprintln!("{}", e);
+            status = 1;
         }
     }
+    // Exiting skips destructors, so the leak report needs an explicit drop.
+    drop(interp);
+    process::exit(status);
 }
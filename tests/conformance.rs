@@ -0,0 +1,130 @@
+// Runs the Forth 2012 test suite in tests/forth2012 and reports which word
+// sets pass. Tests that are known to fail are listed in known-failures.txt;
+// the run fails when a test outside that list fails or when a listed test
+// starts passing. Set RFORTH_BLESS=1 to rewrite the list from the current
+// results, and pass --nocapture to see the report. tests/forth2012/README.md
+// says where the test files come from.
+use rfort::ForthInterp;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const WORD_SETS: &[&str] = &["core", "coreext", "double", "string", "memory", "locals"];
+
+struct WordSet {
+    name: &'static str,
+    passed: Vec<String>,
+    failed: Vec<String>,
+}
+
+fn suite_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/forth2012")
+}
+
+fn is_test(line: &str) -> bool {
+    line.trim_start().to_lowercase().starts_with("t{")
+}
+
+fn test_key(word_set: &str, line: &str) -> String {
+    let line: Vec<&str> = line.split_whitespace().collect();
+    format!("{}: {}", word_set, line.join(" "))
+}
+
+// Evaluates each blank-line separated paragraph on its own, so that a missing
+// word only fails the tests next to it.
+fn run_word_set(name: &'static str) -> WordSet {
+    let path = suite_dir().join(format!("{}.fth", name));
+    let src = fs::read_to_string(&path).expect("cannot read test file");
+    let lines: Vec<&str> = src.lines().collect();
+    let mut interp = ForthInterp::new();
+    interp.set_transactional(true);
+    let mut res = WordSet {
+        name,
+        passed: vec![],
+        failed: vec![],
+    };
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start..lines.len())
+            .find(|&i| lines[i].trim().is_empty())
+            .unwrap_or(lines.len());
+        // Leading newlines keep the line numbers of test failures file-relative.
+        let chunk = "\n".repeat(start) + &lines[start..end].join("\n");
+        let ok = interp.eval_str(&chunk).is_ok();
+        interp.clear_stack();
+        let failed: BTreeSet<usize> = interp
+            .take_test_results()
+            .failures
+            .iter()
+            .map(|f| f.line)
+            .collect();
+        for (i, line) in lines.iter().enumerate().take(end).skip(start) {
+            if !is_test(line) {
+                continue;
+            }
+            if ok && !failed.contains(&(i + 1)) {
+                res.passed.push(test_key(name, line));
+            } else {
+                res.failed.push(test_key(name, line));
+            }
+        }
+        start = end + 1;
+    }
+    res
+}
+
+#[test]
+fn forth2012_conformance() {
+    let results: Vec<WordSet> = WORD_SETS.iter().map(|name| run_word_set(name)).collect();
+
+    println!("{:<10} {:>7} {:>7}", "word set", "passed", "total");
+    for set in &results {
+        let total = set.passed.len() + set.failed.len();
+        let status = if set.failed.is_empty() { "ok" } else { "" };
+        println!(
+            "{:<10} {:>7} {:>7}  {}",
+            set.name,
+            set.passed.len(),
+            total,
+            status
+        );
+    }
+
+    let failed: BTreeSet<String> = results
+        .iter()
+        .flat_map(|set| set.failed.iter().cloned())
+        .collect();
+    let known_path = suite_dir().join("known-failures.txt");
+    if env::var_os("RFORTH_BLESS").is_some() {
+        let mut list = String::new();
+        for key in &failed {
+            list.push_str(key);
+            list.push('\n');
+        }
+        fs::write(&known_path, list).expect("cannot write known failures");
+        return;
+    }
+    let known: BTreeSet<String> = fs::read_to_string(&known_path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.to_string())
+        .collect();
+
+    let regressions: Vec<&String> = failed.difference(&known).collect();
+    let fixed: Vec<&String> = known.difference(&failed).collect();
+    for key in &regressions {
+        println!("unexpected failure: {}", key);
+    }
+    for key in &fixed {
+        println!("now passing: {}", key);
+    }
+    assert!(
+        regressions.is_empty() && fixed.is_empty(),
+        "{} unexpected failures, {} known failures now pass; \
+         rerun with RFORTH_BLESS=1 to update known-failures.txt",
+        regressions.len(),
+        fixed.len()
+    );
+}